@@ -0,0 +1,60 @@
+use chrono::Duration;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ev_flex::{Demands, EnergyDemand};
+use std::thread;
+
+fn demand(vehicle: usize) -> EnergyDemand {
+    let example = EnergyDemand::example(&format!("vehicle-{}", vehicle));
+    let start = example.start + Duration::minutes((vehicle % 16 * 15) as i64);
+    EnergyDemand {
+        current_soc: 10 + (vehicle % 30) as i32,
+        start,
+        end: start + Duration::hours(12),
+        fleet: Some(format!("fleet-{}", vehicle % 4)),
+        ..example
+    }
+}
+
+const VEHICLES: usize = 1000;
+const OPERATIONS: usize = 200;
+
+// Submitting threads update demands of a filled store while the others
+// read the fleet envelope, as the server does under mixed load.
+fn mixed_load(c: &mut Criterion) {
+    let db = Demands::new();
+    for vehicle in 0..VEHICLES {
+        db.insert(demand(vehicle));
+    }
+    let mut group = c.benchmark_group("mixed_load");
+    for (writers, readers) in [(4, 0), (0, 4), (2, 2), (4, 4)] {
+        group.throughput(Throughput::Elements(
+            ((writers + readers) * OPERATIONS) as u64,
+        ));
+        let id = BenchmarkId::from_parameter(format!("{}w_{}r", writers, readers));
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for writer in 0..writers {
+                        let db = &db;
+                        scope.spawn(move || {
+                            for i in 0..OPERATIONS {
+                                db.insert(demand((writer * OPERATIONS + i) % VEHICLES));
+                            }
+                        });
+                    }
+                    for _ in 0..readers {
+                        scope.spawn(|| {
+                            for _ in 0..OPERATIONS {
+                                criterion::black_box(db.aggregation());
+                            }
+                        });
+                    }
+                });
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mixed_load);
+criterion_main!(benches);
@@ -1,21 +1,43 @@
-use crate::demand::EnergyDemand;
-use crate::utils::MinuteDateRange;
-use chrono::{DateTime, Duration, Timelike, Utc};
+use crate::demand::{EnergyDemand, Group, GroupKey};
+use crate::envelope::{PiecewiseLinear, VehicleEnvelope};
+use crate::flexibility::{power_flex_series, PowerFlexDT};
+use crate::utils::TimeRange;
+use chrono::{DateTime, FixedOffset, Timelike, Utc};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub const AGGREGATION_FREQ_MINUTES: u32 = 15;
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct AggregationDT {
-    pub min_soe: i32,            // minimum state of charge in Wh
-    pub max_soe: i32,            // maximum state of charge in Wh
-    pub max_charging_power: i32, // maximum charging power in W
+    pub min_soe: i64,            // minimum state of charge in Wh
+    pub max_soe: i64,            // maximum state of charge in Wh
+    pub max_charging_power: i64, // maximum charging power in W
+    pub baseline_soe: i64,       // state of charge under uncontrolled (asap) charging in Wh
+    pub baseline_power: i64,     // charging power under uncontrolled (asap) charging in W
     pub time: DateTime<Utc>,     // time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_time: Option<DateTime<FixedOffset>>, // time in the market timezone, if aligned
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Aggregation {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
     pub series: Vec<AggregationDT>,
+    pub flexibility: Vec<PowerFlexDT>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GroupAggregation {
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub aggregation: Aggregation,
 }
 
 impl Default for Aggregation {
@@ -24,78 +46,602 @@ impl Default for Aggregation {
             start: Utc::now(),
             end: Utc::now(),
             series: vec![],
+            flexibility: vec![],
         }
     }
 }
 
+/// Flex series of a single vehicle at one minute resolution.
 pub fn create_flex_series(demand: &EnergyDemand) -> Option<Vec<AggregationDT>> {
-    let mut aggregation_series = vec![];
-    let one_percent_energy = demand.capacity / 100;
-    let energy_demand = (demand.target_soc - demand.current_soc) * one_percent_energy;
-    let critical_demand = match demand.min_soc > demand.current_soc {
-        true => (demand.min_soc - demand.current_soc) * one_percent_energy,
-        false => 0,
-    };
-    let time_to_charge_max_soe = (energy_demand * 60 / demand.max_charging_power) as i64;
-    let time_to_charge_critical_soe = (critical_demand * 60 / demand.max_charging_power) as i64;
-    let one_minute_energy_state_change = demand.max_charging_power / 60;
+    flex_series(demand, 1)
+}
+
+/// Flex series of a single vehicle at the times of the `freq_minutes` grid
+/// within the session.
+pub fn flex_series(demand: &EnergyDemand, freq_minutes: u32) -> Option<Vec<AggregationDT>> {
+    Some(VehicleEnvelope::from_demand(demand)?.sample(freq_minutes))
+}
 
-    let asap_charge_time_end = demand.start + Duration::minutes(time_to_charge_max_soe);
-    let alap_charge_time_start = demand.end - Duration::minutes(time_to_charge_max_soe)
-        + Duration::minutes(time_to_charge_critical_soe);
+pub fn resample_series(series: &mut Vec<AggregationDT>, freq_minutes: u32) {
+    series.retain(|x| x.time.minute() % freq_minutes == 0);
+}
 
-    if asap_charge_time_end > demand.end {
-        println!("UNFEASIBLE DEMAND");
-        return None;
+/// Flex series of a single vehicle as it contributes to the fleet envelope,
+/// at `AGGREGATION_FREQ_MINUTES`.
+pub fn vehicle_series(demand: &EnergyDemand) -> Option<Vec<AggregationDT>> {
+    flex_series(demand, AGGREGATION_FREQ_MINUTES)
+}
+
+/// Envelope of a single vehicle, including its baseline profile.
+pub fn vehicle_aggregation(demand: &EnergyDemand) -> Aggregation {
+    let series = vehicle_series(demand).unwrap_or_default();
+    Aggregation {
+        start: demand.start,
+        end: demand.end,
+        flexibility: power_flex_series(&series),
+        series,
     }
+}
 
-    let mut max_soe_state = demand.current_soc * one_percent_energy;
-    let mut min_soe_state = demand.current_soc * one_percent_energy;
+#[derive(Default)]
+struct FlexPoint {
+    // flexibility of the vehicles with an interval starting at this time
+    intervals: i64,
+    min_power: i64,
+    max_power: i64,
+    upward_flex: i64,
+    downward_flex: i64,
+    // vehicles per sustainable duration of their min_power and max_power
+    upward_durations: BTreeMap<i64, i64>,
+    downward_durations: BTreeMap<i64, i64>,
+}
 
-    let min_soe = demand.min_soc * one_percent_energy;
+fn count_duration(durations: &mut BTreeMap<i64, i64>, duration: i64, count: i64) {
+    let vehicles = durations.entry(duration).or_default();
+    *vehicles += count;
+    if *vehicles == 0 {
+        durations.remove(&duration);
+    }
+}
 
-    // Fill asap line
-    for time in MinuteDateRange(demand.start, asap_charge_time_end) {
-        let aggregation_dt = AggregationDT {
-            min_soe: min_soe_state,
-            max_soe: max_soe_state,
-            max_charging_power: demand.max_charging_power,
-            time: time,
-        };
+impl FlexPoint {
+    fn apply_flex(&mut self, flex: &PowerFlexDT, weight: f64) {
+        let (sign, scale) = (weight.signum() as i64, |value: i64| {
+            (value as f64 * weight).round() as i64
+        });
+        self.intervals += sign;
+        self.min_power += scale(flex.min_power);
+        self.max_power += scale(flex.max_power);
+        self.upward_flex += scale(flex.upward_flex);
+        self.downward_flex += scale(flex.downward_flex);
+        count_duration(&mut self.upward_durations, flex.upward_duration, sign);
+        count_duration(&mut self.downward_durations, flex.downward_duration, sign);
+    }
 
-        aggregation_series.push(aggregation_dt);
+    fn is_empty(&self) -> bool {
+        self.intervals == 0
+            && [
+                self.min_power,
+                self.max_power,
+                self.upward_flex,
+                self.downward_flex,
+            ] == [0; 4]
+            && self.upward_durations.is_empty()
+            && self.downward_durations.is_empty()
+    }
 
-        max_soe_state += one_minute_energy_state_change;
+    fn merge(&mut self, other: FlexPoint) {
+        self.intervals += other.intervals;
+        self.min_power += other.min_power;
+        self.max_power += other.max_power;
+        self.upward_flex += other.upward_flex;
+        self.downward_flex += other.downward_flex;
+        for (duration, count) in other.upward_durations {
+            count_duration(&mut self.upward_durations, duration, count);
+        }
+        for (duration, count) in other.downward_durations {
+            count_duration(&mut self.downward_durations, duration, count);
+        }
+    }
 
-        if min_soe_state + one_minute_energy_state_change < min_soe {
-            min_soe_state += one_minute_energy_state_change;
+    // the summed powers last as long as the vehicle holding its power the shortest
+    fn to_flex(&self, time: DateTime<Utc>) -> PowerFlexDT {
+        let shortest = |durations: &BTreeMap<i64, i64>| durations.keys().next().copied();
+        PowerFlexDT {
+            min_power: self.min_power,
+            max_power: self.max_power,
+            upward_flex: self.upward_flex,
+            downward_flex: self.downward_flex,
+            upward_duration: shortest(&self.upward_durations).unwrap_or(0),
+            downward_duration: shortest(&self.downward_durations).unwrap_or(0),
+            time,
         }
     }
+}
 
-    // Fill middle area
-    for time in MinuteDateRange(asap_charge_time_end, alap_charge_time_start) {
-        aggregation_series.push(AggregationDT {
-            min_soe: min_soe_state,
-            max_soe: max_soe_state,
-            max_charging_power: demand.max_charging_power,
-            time: time,
-        });
+// power flexibility of a single vehicle, per interval of its series
+fn vehicle_flexibility(vehicle: &VehicleEnvelope) -> Vec<PowerFlexDT> {
+    power_flex_series(&vehicle.sample(AGGREGATION_FREQ_MINUTES))
+}
+
+/// Sum of all vehicle envelopes, updated incrementally as demands come and go.
+///
+/// The envelopes are summed as breakpoints, every vehicle counting as zero
+/// outside of its session, and sampled at `AGGREGATION_FREQ_MINUTES` where
+/// vehicles are present. The power flexibility is computed per vehicle and
+/// summed alongside, so that vehicles arriving or leaving are not mistaken
+/// for charged energy.
+#[derive(Default)]
+pub struct FleetEnvelope {
+    vehicles: i64,
+    min_soe: PiecewiseLinear,
+    max_soe: PiecewiseLinear,
+    max_charging_power: PiecewiseLinear,
+    baseline_power: PiecewiseLinear,
+    // vehicles present at any time
+    present: PiecewiseLinear,
+    flexibility: BTreeMap<DateTime<Utc>, FlexPoint>,
+}
+
+impl FleetEnvelope {
+    pub fn add(&mut self, vehicle: &VehicleEnvelope) {
+        self.apply(vehicle, &vehicle_flexibility(vehicle), 1.0);
     }
 
-    // Fill alap line
-    for time in MinuteDateRange(alap_charge_time_start, demand.end) {
-        aggregation_series.push(AggregationDT {
-            min_soe: min_soe_state,
-            max_soe: max_soe_state,
-            max_charging_power: demand.max_charging_power,
-            time: time,
-        });
-        min_soe_state += one_minute_energy_state_change;
+    pub fn remove(&mut self, vehicle: &VehicleEnvelope) {
+        self.apply(vehicle, &vehicle_flexibility(vehicle), -1.0);
+        self.settle();
+    }
+
+    /// Adds a vehicle expected with the probability `weight`, which scales
+    /// its energies and powers but not how long it can hold them.
+    pub fn add_weighted(&mut self, vehicle: &VehicleEnvelope, weight: f64) {
+        self.apply(vehicle, &vehicle_flexibility(vehicle), weight);
+    }
+
+    fn apply(&mut self, vehicle: &VehicleEnvelope, flexibility: &[PowerFlexDT], weight: f64) {
+        let session = |value: f64| PiecewiseLinear::constant(vehicle.start, vehicle.end, value);
+        self.vehicles += weight.signum() as i64;
+        self.min_soe = self.min_soe.add(&vehicle.min_soe.scaled(weight));
+        self.max_soe = self.max_soe.add(&vehicle.max_soe.scaled(weight));
+        self.max_charging_power =
+            (self.max_charging_power).add(&session(vehicle.max_charging_power as f64 * weight));
+        self.baseline_power = (self.baseline_power).add(&vehicle.baseline_power().scaled(weight));
+        self.present = self.present.add(&session(weight.signum()));
+        for flex in flexibility {
+            let point = self.flexibility.entry(flex.time).or_default();
+            point.apply_flex(flex, weight);
+            if point.is_empty() {
+                self.flexibility.remove(&flex.time);
+            }
+        }
+    }
+
+    /// Adds all vehicles of `other`.
+    pub fn merge(mut self, other: FleetEnvelope) -> FleetEnvelope {
+        self.vehicles += other.vehicles;
+        self.min_soe = self.min_soe.add(&other.min_soe);
+        self.max_soe = self.max_soe.add(&other.max_soe);
+        self.max_charging_power = self.max_charging_power.add(&other.max_charging_power);
+        self.baseline_power = self.baseline_power.add(&other.baseline_power);
+        self.present = self.present.add(&other.present);
+        for (time, other) in other.flexibility {
+            let point = self.flexibility.entry(time).or_default();
+            point.merge(other);
+            if point.is_empty() {
+                self.flexibility.remove(&time);
+            }
+        }
+        self.settle();
+        self
+    }
+
+    // once all vehicles are gone, nothing is left but the rounding of the sums
+    fn settle(&mut self) {
+        if self.vehicles == 0 {
+            *self = FleetEnvelope::default();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vehicles == 0
+    }
+
+    // times of the aggregation grid at which any vehicle is present
+    fn times(&self) -> Vec<DateTime<Utc>> {
+        let points = self.present.points();
+        let mut times: Vec<DateTime<Utc>> = points
+            .windows(2)
+            .filter(|w| w[0].value >= 0.5 || w[1].value >= 0.5)
+            .flat_map(|w| {
+                TimeRange::minutes(w[0].time, w[1].time, AGGREGATION_FREQ_MINUTES)
+                    .aligned()
+                    .inclusive()
+            })
+            .filter(|time| {
+                self.present
+                    .at(*time)
+                    .is_some_and(|vehicles| vehicles >= 0.5)
+            })
+            .collect();
+        times.dedup();
+        times
     }
 
-    Some(aggregation_series)
+    pub fn to_aggregation(&self) -> Aggregation {
+        let times = self.times();
+        let mut aggregation = Aggregation::default();
+        if let (Some(first), Some(last)) = (times.first(), times.last()) {
+            aggregation.start = *first;
+            aggregation.end = *last;
+        }
+        aggregation.series = times
+            .into_iter()
+            .map(|time| {
+                let value = |f: &PiecewiseLinear| f.at(time).unwrap_or(0.0).round() as i64;
+                AggregationDT {
+                    min_soe: value(&self.min_soe),
+                    max_soe: value(&self.max_soe),
+                    max_charging_power: value(&self.max_charging_power),
+                    baseline_soe: value(&self.max_soe),
+                    baseline_power: value(&self.baseline_power),
+                    time,
+                    local_time: None,
+                }
+            })
+            .collect();
+        aggregation.flexibility = self
+            .flexibility
+            .iter()
+            .map(|(time, point)| point.to_flex(*time))
+            .collect();
+        aggregation
+    }
 }
 
-pub fn resample_series(series: &mut Vec<AggregationDT>, freq_minutes: u32) {
-    series.retain(|x| x.time.minute() % freq_minutes == 0);
+/// Envelope of the demands of distinct vehicles, computing and summing the
+/// vehicle envelopes in parallel with the `parallel` feature.
+#[cfg(feature = "parallel")]
+pub fn fleet_aggregation(demands: &[EnergyDemand]) -> Aggregation {
+    demands
+        .par_iter()
+        .filter_map(VehicleEnvelope::from_demand)
+        .fold(FleetEnvelope::default, |mut envelope, vehicle| {
+            envelope.add(&vehicle);
+            envelope
+        })
+        .reduce(FleetEnvelope::default, FleetEnvelope::merge)
+        .to_aggregation()
+}
+
+/// Envelope of the demands of distinct vehicles.
+#[cfg(not(feature = "parallel"))]
+pub fn fleet_aggregation(demands: &[EnergyDemand]) -> Aggregation {
+    let mut envelope = FleetEnvelope::default();
+    for vehicle in demands.iter().filter_map(VehicleEnvelope::from_demand) {
+        envelope.add(&vehicle);
+    }
+    envelope.to_aggregation()
+}
+
+/// What a vehicle adds to the envelopes.
+pub(crate) struct Contribution {
+    pub groups: Vec<Group>,
+    pub envelope: VehicleEnvelope,
+    // envelope per confidence level, for vehicles that may leave before their
+    // end, None where the target cannot be reached by then
+    pub confidence: BTreeMap<u32, Option<VehicleEnvelope>>,
+}
+
+/// Fleet envelope plus one envelope per value of every grouping key and per
+/// confidence level.
+#[derive(Default)]
+pub(crate) struct Envelopes {
+    pub total: FleetEnvelope,
+    groups: HashMap<Group, FleetEnvelope>,
+    confidence: BTreeMap<u32, FleetEnvelope>,
+    // what each vehicle added, so that it is removed exactly as it was added
+    vehicles: HashMap<String, Contribution>,
+}
+
+impl Envelopes {
+    pub fn new(confidence_levels: &[u32]) -> Self {
+        Envelopes {
+            confidence: confidence_levels
+                .iter()
+                .map(|level| (*level, FleetEnvelope::default()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the contribution of a vehicle, `None` removes the vehicle.
+    pub fn set_vehicle(&mut self, vehicle_id: &str, contribution: Option<Contribution>) {
+        // the change of every envelope is summed on its own first, so that
+        // the large envelopes are only added to once
+        let levels: Vec<u32> = self.confidence.keys().copied().collect();
+        let mut change = Envelopes::new(&levels);
+        if let Some(previous) = self.vehicles.remove(vehicle_id) {
+            change.apply(&previous, -1.0);
+        }
+        if let Some(contribution) = &contribution {
+            change.apply(contribution, 1.0);
+        }
+        self.merge(change);
+        if let Some(contribution) = contribution {
+            self.vehicles.insert(vehicle_id.to_string(), contribution);
+        }
+    }
+
+    fn merge(&mut self, change: Envelopes) {
+        self.total = std::mem::take(&mut self.total).merge(change.total);
+        for (group, change) in change.groups {
+            let envelope = self.groups.remove(&group).unwrap_or_default().merge(change);
+            if !envelope.is_empty() {
+                self.groups.insert(group, envelope);
+            }
+        }
+        for (level, change) in change.confidence {
+            if let Some(envelope) = self.confidence.get_mut(&level) {
+                *envelope = std::mem::take(envelope).merge(change);
+            }
+        }
+    }
+
+    fn apply(&mut self, contribution: &Contribution, weight: f64) {
+        let vehicle = &contribution.envelope;
+        let flexibility = vehicle_flexibility(vehicle);
+        self.total.apply(vehicle, &flexibility, weight);
+        for group in &contribution.groups {
+            let envelope = self.groups.entry(group.clone()).or_default();
+            envelope.apply(vehicle, &flexibility, weight);
+        }
+        for (level, envelope) in self.confidence.iter_mut() {
+            match contribution.confidence.get(level) {
+                Some(Some(vehicle)) => {
+                    envelope.apply(vehicle, &vehicle_flexibility(vehicle), weight)
+                }
+                Some(None) => {}
+                None => envelope.apply(vehicle, &flexibility, weight),
+            }
+        }
+    }
+
+    pub fn at_confidence(&self, level: u32) -> Option<Aggregation> {
+        Some(self.confidence.get(&level)?.to_aggregation())
+    }
+
+    pub fn grouped(&self, key: GroupKey) -> Vec<GroupAggregation> {
+        let mut groups: Vec<GroupAggregation> = self
+            .groups
+            .iter()
+            .filter(|((group_key, _), _)| *group_key == key)
+            .map(|((_, group), envelope)| GroupAggregation {
+                group: group.clone(),
+                aggregation: envelope.to_aggregation(),
+            })
+            .collect();
+        groups.sort_by(|a, b| a.group.cmp(&b.group));
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::{
+        resample_series, vehicle_series, AggregationDT, Contribution, Envelopes, FleetEnvelope,
+    };
+    use crate::demand::{EnergyDemand, GroupKey};
+    use crate::envelope::VehicleEnvelope;
+    #[cfg(feature = "generator")]
+    use crate::{
+        aggregation::fleet_aggregation,
+        demand::Demands,
+        generator::{generate_fleet, FleetConfig},
+    };
+    use chrono::{Duration, TimeZone, Timelike, Utc};
+
+    #[test]
+    fn test_vehicle_series_unique_times() {
+        let series = vehicle_series(&EnergyDemand::example("a")).unwrap();
+        assert!(series.windows(2).all(|w| w[0].time < w[1].time));
+        assert!(series.iter().all(|dt| dt.time.timestamp() % 900 == 0));
+    }
+
+    #[test]
+    fn test_resampling() {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 18, 7, 0).unwrap();
+        let demand = EnergyDemand::example_session("a", start, start + Duration::minutes(705));
+        let series = vehicle_series(&demand).unwrap();
+        assert!(series
+            .iter()
+            .all(|dt| dt.time.minute() % 15 == 0 && dt.time.second() == 0));
+
+        let time = Utc.with_ymd_and_hms(2023, 5, 1, 18, 0, 0).unwrap();
+        let mut series: Vec<AggregationDT> = (0..60)
+            .map(|minute| AggregationDT {
+                min_soe: 0,
+                max_soe: 0,
+                max_charging_power: 0,
+                baseline_soe: 0,
+                baseline_power: 0,
+                time: time + Duration::minutes(minute),
+                local_time: None,
+            })
+            .collect();
+        resample_series(&mut series, 30);
+        assert_eq!(series.len(), 2);
+    }
+
+    #[test]
+    fn test_fleet_envelope_add_remove() {
+        let a = VehicleEnvelope::from_demand(&EnergyDemand::example("a")).unwrap();
+        let b = VehicleEnvelope::from_demand(&EnergyDemand::example("b")).unwrap();
+        let series = vehicle_series(&EnergyDemand::example("a")).unwrap();
+        let mut envelope = FleetEnvelope::default();
+        envelope.add(&a);
+        envelope.add(&b);
+        let aggregation = envelope.to_aggregation();
+        assert_eq!(aggregation.series.len(), series.len());
+        assert_eq!(aggregation.series[0].max_soe, 2 * series[0].max_soe);
+
+        envelope.remove(&a);
+        assert_eq!(
+            envelope.to_aggregation().series[0].max_soe,
+            series[0].max_soe
+        );
+        envelope.remove(&b);
+        assert!(envelope.is_empty());
+        assert!(envelope.to_aggregation().series.is_empty());
+    }
+
+    #[test]
+    fn test_fleet_envelope_sessions_not_aligned() {
+        // "b" arrives and leaves between the rows of "a"
+        let a = EnergyDemand::example("a");
+        let mut b = EnergyDemand::example("b");
+        b.start = Utc.with_ymd_and_hms(2023, 5, 1, 18, 7, 0).unwrap();
+        b.end = Utc.with_ymd_and_hms(2023, 5, 2, 4, 53, 0).unwrap();
+        let mut envelope = FleetEnvelope::default();
+        for demand in [&a, &b] {
+            envelope.add(&VehicleEnvelope::from_demand(demand).unwrap());
+        }
+        let aggregation = envelope.to_aggregation();
+        let (a, b) = (vehicle_series(&a).unwrap(), vehicle_series(&b).unwrap());
+        assert_eq!(aggregation.series.len(), a.len());
+        // every row sums the vehicles present at its time
+        for dt in &aggregation.series {
+            let rows: Vec<_> = a
+                .iter()
+                .chain(&b)
+                .filter(|row| row.time == dt.time)
+                .collect();
+            let sum =
+                |field: fn(&AggregationDT) -> i64| rows.iter().map(|row| field(row)).sum::<i64>();
+            assert_eq!(dt.min_soe, sum(|row| row.min_soe));
+            assert_eq!(dt.max_soe, sum(|row| row.max_soe));
+            assert_eq!(dt.max_charging_power, sum(|row| row.max_charging_power));
+            assert_eq!(dt.baseline_power, sum(|row| row.baseline_power));
+        }
+        let at = |hour, minute| {
+            let time = Utc.with_ymd_and_hms(2023, 5, 1, hour, minute, 0).unwrap();
+            aggregation
+                .series
+                .iter()
+                .find(|dt| dt.time == time)
+                .unwrap()
+        };
+        assert_eq!(at(18, 0).max_charging_power, 11000);
+        assert_eq!(at(18, 15).max_charging_power, 22000);
+    }
+
+    #[test]
+    fn test_flex_durations_ignore_arrivals() {
+        // "b" arrives while "a" is about to finish charging
+        let a = EnergyDemand::example("a");
+        let mut b = EnergyDemand::example("b");
+        b.start = Utc.with_ymd_and_hms(2023, 5, 1, 22, 0, 0).unwrap();
+        let mut envelope = FleetEnvelope::default();
+        envelope.add(&VehicleEnvelope::from_demand(&a).unwrap());
+        envelope.add(&VehicleEnvelope::from_demand(&b).unwrap());
+        let aggregation = envelope.to_aggregation();
+        let at = |hour| {
+            let time = Utc.with_ymd_and_hms(2023, 5, 1, hour, 0, 0).unwrap();
+            aggregation
+                .flexibility
+                .iter()
+                .find(|flex| flex.time == time)
+                .unwrap()
+        };
+        // "a" alone is full shortly before 22:00
+        assert_eq!(at(21).upward_duration, 45);
+        assert_eq!(at(22).upward_duration, 225);
+        assert_eq!(aggregation.flexibility.len(), aggregation.series.len() - 1);
+    }
+
+    #[test]
+    fn test_fleet_envelope_beyond_i32() {
+        let mut large = EnergyDemand::example("a");
+        large.capacity = 2_000_000_000;
+        large.max_charging_power = 2_000_000_000;
+        let vehicle = VehicleEnvelope::from_demand(&large).unwrap();
+        let mut envelope = FleetEnvelope::default();
+        envelope.add(&vehicle);
+        envelope.add(&vehicle);
+        let last = envelope.to_aggregation().series.pop().unwrap();
+        assert_eq!(last.max_soe, 3_200_000_000);
+        assert_eq!(last.min_soe, 3_200_000_000);
+    }
+
+    #[test]
+    fn test_baseline_follows_asap_charging() {
+        let series = vehicle_series(&EnergyDemand::example("a")).unwrap();
+        let first = &series[0];
+        let last = series.last().unwrap();
+        assert_eq!(first.baseline_power, 11000);
+        assert_eq!(last.baseline_power, 0);
+        assert_eq!(last.baseline_soe, last.max_soe);
+        assert!(series
+            .windows(2)
+            .all(|w| w[0].baseline_soe <= w[1].baseline_soe));
+    }
+
+    #[test]
+    fn test_envelopes_grouped() {
+        let mut a = EnergyDemand::example("a");
+        a.fleet = Some("north".to_string());
+        let b = EnergyDemand::example("b");
+        let contribution = |demand: &EnergyDemand| Contribution {
+            groups: demand.groups(),
+            envelope: VehicleEnvelope::from_demand(demand).unwrap(),
+            confidence: Default::default(),
+        };
+        let mut envelopes = Envelopes::default();
+        envelopes.set_vehicle("a", Some(contribution(&a)));
+        envelopes.set_vehicle("b", Some(contribution(&b)));
+        let groups = envelopes.grouped(GroupKey::Fleet);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].group, None);
+        assert_eq!(groups[1].group, Some("north".to_string()));
+
+        envelopes.set_vehicle("a", None);
+        assert_eq!(envelopes.grouped(GroupKey::Fleet).len(), 1);
+
+        // a later session of "b" replaces the earlier one
+        let mut later = b.clone();
+        later.start = Utc.with_ymd_and_hms(2023, 5, 1, 20, 0, 0).unwrap();
+        envelopes.set_vehicle("b", Some(contribution(&later)));
+        let aggregation = envelopes.total.to_aggregation();
+        assert_eq!(aggregation.start, later.start);
+        assert_eq!(
+            aggregation.series[0].max_charging_power,
+            later.max_charging_power as i64
+        );
+    }
+
+    #[cfg(feature = "generator")]
+    #[test]
+    fn test_parallel_matches_serial() {
+        let fleet = generate_fleet(&FleetConfig {
+            vehicles: 300,
+            fleets: 3,
+            ..Default::default()
+        });
+        let serial = Demands::new();
+        for demand in fleet.clone() {
+            serial.insert(demand);
+        }
+        let parallel = Demands::new();
+        parallel.insert_many(fleet.clone());
+
+        let expected = serde_json::to_value(serial.aggregation()).unwrap();
+        assert_eq!(
+            serde_json::to_value(parallel.aggregation()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            serde_json::to_value(fleet_aggregation(&fleet)).unwrap(),
+            expected
+        );
+    }
 }
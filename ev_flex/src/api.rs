@@ -1,29 +1,734 @@
-use crate::aggregation::{create_flex_series, resample_series, Aggregation};
-use crate::demand::{Demands, EnergyDemand};
-use actix_web::{get, post, web, web::Data, Responder};
+use crate::aggregation::{Aggregation, GroupAggregation};
+use crate::demand::{Demands, EnergyDemand, GroupKey, SocReading};
+use crate::envelope::VehicleEnvelope;
+#[cfg(feature = "parquet")]
+use crate::export::to_parquet;
+use crate::export::{to_csv, to_rows, ExportFormat, ExportRow};
+use crate::forecast::{forecast, Forecast};
+use crate::market::MarketPeriods;
+use crate::policy::DegradationPolicy;
+use crate::reserve::{qualify, ReserveProduct, ReserveQualification};
+use crate::schedule::{RecurringDemand, Schedules};
+use crate::settlement::{
+    settle_days, settle_sessions, DaySettlement, SessionSettlement, SettlementLevel,
+};
+use crate::webhook::{Delivery, EventType, Subscription, Webhooks};
+use actix_web::http::header::ACCEPT;
+use actix_web::{delete, get, post, put, web, web::Data, HttpRequest, HttpResponse, Responder};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
 
+const NDJSON: &str = "application/x-ndjson";
+/// Largest body `POST /demands` accepts, room for some 100,000 demands.
+pub const BULK_PAYLOAD_LIMIT: usize = 32 * 1024 * 1024;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FormatQuery {
+    format: Option<ExportFormat>,
+}
+
+// An explicit `format` query parameter wins over the Accept header.
+fn negotiate_format(request: &HttpRequest, format: Option<ExportFormat>) -> ExportFormat {
+    format.unwrap_or_else(|| {
+        let accept = request.headers().get(ACCEPT);
+        ExportFormat::from_accept(accept.and_then(|v| v.to_str().ok()).unwrap_or(""))
+    })
+}
+
+fn export_response(
+    format: ExportFormat,
+    json: &impl Serialize,
+    rows: impl FnOnce() -> Vec<ExportRow>,
+) -> HttpResponse {
+    let body = match format {
+        ExportFormat::Json => serde_json::to_vec(json).map_err(std::io::Error::from),
+        ExportFormat::Csv => to_csv(&rows()),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => to_parquet(&rows()),
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[utoipa::path(
+    request_body = EnergyDemand,
+    responses(
+        (status = 200, description = "Demand stored", body = String),
+        (status = 400, description = "Malformed or invalid demand", body = String),
+    )
+)]
 #[post("/demand")]
 pub async fn handle_energy_demand(
     db: Data<Demands>,
     new_demand: web::Json<EnergyDemand>,
 ) -> impl Responder {
     println!("{}", serde_json::to_string_pretty(&new_demand).unwrap());
-    let response = format!("Received demand for {}!", new_demand.vehicle_id);
-    db.insert(new_demand.into_inner());
-    response
+    if let Err(err) = new_demand.validate() {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let vehicle_id = new_demand.vehicle_id.clone();
+    let message = match db.insert(new_demand.into_inner()) {
+        Some(_) => format!("Updated demand for {}!", vehicle_id),
+        None => format!("Received demand for {}!", vehicle_id),
+    };
+    HttpResponse::Ok().body(message)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkStatus {
+    Received,
+    Updated,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct BulkResult {
+    pub index: usize,
+    pub vehicle_id: Option<String>,
+    pub status: BulkStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Items of a JSON array, or of newline delimited JSON, each parsed on its own
+// so that one broken demand does not reject the others.
+fn parse_bulk(body: &[u8]) -> Result<Vec<serde_json::Result<Value>>, String> {
+    let body = std::str::from_utf8(body).map_err(|err| err.to_string())?;
+    if body.trim_start().starts_with('[') {
+        let items: Vec<Value> = serde_json::from_str(body).map_err(|err| err.to_string())?;
+        return Ok(items.into_iter().map(Ok).collect());
+    }
+    Ok(body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect())
+}
+
+// vehicle of a bulk item if it has one, and its demand or why it is rejected
+fn parse_item(item: serde_json::Result<Value>) -> (Option<String>, Result<EnergyDemand, String>) {
+    let vehicle_id = item.as_ref().ok().and_then(|value| {
+        let vehicle_id = value.get("vehicle_id")?.as_str()?;
+        Some(vehicle_id.to_string())
+    });
+    let demand = item
+        .and_then(serde_json::from_value::<EnergyDemand>)
+        .map_err(|err| err.to_string())
+        .and_then(|demand| demand.validate().map(|_| demand));
+    (vehicle_id, demand)
+}
+
+/// Submits many demands at once, as a JSON array or newline delimited JSON.
+///
+/// Registered in [`configure`] with a payload limit of [`BULK_PAYLOAD_LIMIT`].
+#[utoipa::path(
+    post,
+    path = "/demands",
+    request_body(
+        content = Vec<EnergyDemand>,
+        description = "JSON array, or one demand per line as application/x-ndjson"
+    ),
+    responses(
+        (status = 200, description = "Result per submitted demand", body = Vec<BulkResult>),
+        (status = 400, description = "Body is neither a JSON array nor NDJSON", body = String),
+        (status = 413, description = "Body is larger than the payload limit"),
+    )
+)]
+pub async fn handle_bulk_demands(db: Data<Demands>, body: web::Bytes) -> impl Responder {
+    let items = match parse_bulk(&body) {
+        Ok(items) => items,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let mut valid = vec![];
+    let mut results = vec![];
+    for (index, item) in items.into_iter().enumerate() {
+        let (vehicle_id, demand) = parse_item(item);
+        let (status, error) = match demand {
+            Ok(demand) => {
+                valid.push(demand);
+                (BulkStatus::Received, None)
+            }
+            Err(err) => (BulkStatus::Rejected, Some(err)),
+        };
+        results.push(BulkResult {
+            index,
+            vehicle_id,
+            status,
+            error,
+        });
+    }
+    // the series are computed in parallel, off the async executor
+    let previous = match web::block(move || db.insert_many(valid)).await {
+        Ok(previous) => previous,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let accepted = results
+        .iter_mut()
+        .filter(|result| result.status == BulkStatus::Received);
+    for (result, previous) in accepted.zip(previous) {
+        if previous.is_some() {
+            result.status = BulkStatus::Updated;
+        }
+    }
+    HttpResponse::Ok().json(results)
+}
+
+/// Dumps all stored demands in a form `POST /demands` accepts.
+#[utoipa::path(
+    responses((
+        status = 200,
+        description = "All stored demands, as NDJSON if accepted",
+        content(
+            (Vec<EnergyDemand> = "application/json"),
+            (String = "application/x-ndjson"),
+        )
+    ))
+)]
+#[get("/demands")]
+pub async fn handle_demands_export(db: Data<Demands>, request: HttpRequest) -> impl Responder {
+    let demands = db.all();
+    let accept = request.headers().get(ACCEPT);
+    let ndjson = accept
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(NDJSON));
+    if !ndjson {
+        return HttpResponse::Ok().json(demands);
+    }
+    let mut body = String::new();
+    for demand in &demands {
+        body.push_str(&serde_json::to_string(demand).unwrap());
+        body.push('\n');
+    }
+    HttpResponse::Ok().content_type(NDJSON).body(body)
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Demand removed", body = String),
+        (status = 404, description = "No demand for the vehicle", body = String),
+    )
+)]
+#[delete("/demand/{vehicle_id}")]
+pub async fn handle_demand_removal(
+    db: Data<Demands>,
+    vehicle_id: web::Path<String>,
+) -> impl Responder {
+    match db.remove(&vehicle_id) {
+        Some(_) => HttpResponse::Ok().body(format!("Removed demand for {}!", vehicle_id)),
+        None => HttpResponse::NotFound().body(format!("No demand for {}!", vehicle_id)),
+    }
+}
+
+#[utoipa::path(
+    params(FormatQuery),
+    responses(
+        (status = 200, description = "Envelope of the vehicle", content(
+            (Aggregation = "application/json"),
+            (String = "text/csv"),
+            (Vec<u8> = "application/vnd.apache.parquet"),
+        )),
+        (status = 404, description = "No demand for the vehicle", body = String),
+    )
+)]
+#[get("/demand/{vehicle_id}/series")]
+pub async fn handle_vehicle_series_request(
+    db: Data<Demands>,
+    vehicle_id: web::Path<String>,
+    query: web::Query<FormatQuery>,
+    request: HttpRequest,
+) -> impl Responder {
+    let format = negotiate_format(&request, query.format);
+    match db.vehicle_aggregation(&vehicle_id) {
+        Some(aggregation) => export_response(format, &aggregation, || {
+            to_rows(Some(&vehicle_id), &aggregation)
+        }),
+        None => HttpResponse::NotFound().body(format!("No demand for {}!", vehicle_id)),
+    }
 }
 
+#[utoipa::path(
+    request_body = SocReading,
+    responses(
+        (status = 200, description = "Reading stored", body = String),
+        (status = 400, description = "Malformed reading or state of charge out of range", body = String),
+        (status = 404, description = "No demand for the vehicle", body = String),
+    )
+)]
+#[post("/demand/{vehicle_id}/soc")]
+pub async fn handle_soc_reading(
+    db: Data<Demands>,
+    vehicle_id: web::Path<String>,
+    reading: web::Json<SocReading>,
+) -> impl Responder {
+    if !(0..=100).contains(&reading.soc) {
+        return HttpResponse::BadRequest().body("soc must be between 0 and 100 percent");
+    }
+    match db.add_reading(&vehicle_id, reading.into_inner()) {
+        true => HttpResponse::Ok().body(format!("Received reading for {}!", vehicle_id)),
+        false => HttpResponse::NotFound().body(format!("No demand for {}!", vehicle_id)),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Envelope of the vehicle as breakpoints", body = VehicleEnvelope),
+        (status = 404, description = "No feasible demand for the vehicle", body = String),
+    )
+)]
+#[get("/demand/{vehicle_id}/envelope")]
+pub async fn handle_vehicle_envelope_request(
+    db: Data<Demands>,
+    vehicle_id: web::Path<String>,
+) -> impl Responder {
+    match db.vehicle_envelope(&vehicle_id) {
+        Some(envelope) => HttpResponse::Ok().json(envelope),
+        None => HttpResponse::NotFound().body(format!("No envelope for {}!", vehicle_id)),
+    }
+}
+
+#[utoipa::path(
+    request_body = DegradationPolicy,
+    responses(
+        (status = 200, description = "Policy stored", body = String),
+        (status = 400, description = "Invalid policy", body = String),
+    )
+)]
+#[put("/fleet/{fleet}/policy")]
+pub async fn handle_fleet_policy(
+    db: Data<Demands>,
+    fleet: web::Path<String>,
+    policy: web::Json<DegradationPolicy>,
+) -> impl Responder {
+    if let Err(err) = policy.validate() {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let message = format!("Updated policy for fleet {}!", fleet);
+    // recomputes the series of the whole fleet, off the async executor
+    let fleet = fleet.into_inner();
+    match web::block(move || db.set_fleet_policy(&fleet, policy.into_inner())).await {
+        Ok(()) => HttpResponse::Ok().body(message),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AggregationQuery {
+    group_by: Option<GroupKey>,
+    format: Option<ExportFormat>,
+    tz: Option<String>,              // market timezone, e.g. Europe/Berlin
+    period: Option<u32>,             // delivery period in minutes
+    delivery_day: Option<NaiveDate>, // local delivery day
+    confidence: Option<u32>,         // confidence level in percent
+}
+
+#[utoipa::path(
+    params(AggregationQuery),
+    responses(
+        (
+            status = 200,
+            description = "Fleet envelope, or a list of GroupAggregation with `group_by`",
+            content(
+                (Aggregation = "application/json"),
+                (String = "text/csv"),
+                (Vec<u8> = "application/vnd.apache.parquet"),
+            )
+        ),
+        (status = 400, description = "Invalid market period or confidence level", body = String),
+    )
+)]
 #[get("/aggregation")]
-pub async fn handle_aggregation_request(db: Data<Demands>) -> impl Responder {
-    let demands = db.demands.lock().unwrap();
-    let mut aggregation: Aggregation = Aggregation::default();
-    for demand in demands.iter() {
-        aggregation.start = std::cmp::min(aggregation.start, demand.start);
-        aggregation.end = std::cmp::max(aggregation.end, demand.end);
-        if let Some(mut series) = create_flex_series(demand) {
-            aggregation.series.append(&mut series);
+pub async fn handle_aggregation_request(
+    db: Data<Demands>,
+    query: web::Query<AggregationQuery>,
+    request: HttpRequest,
+) -> impl Responder {
+    let format = negotiate_format(&request, query.format);
+    let periods =
+        match MarketPeriods::from_query(query.tz.as_deref(), query.period, query.delivery_day) {
+            Ok(periods) => periods,
+            Err(err) => return HttpResponse::BadRequest().body(err),
+        };
+    let align = |aggregation: Aggregation| match &periods {
+        Some(periods) => periods.align(aggregation),
+        None => aggregation,
+    };
+    match (query.group_by, query.confidence) {
+        (Some(_), Some(_)) => {
+            HttpResponse::BadRequest().body("Confidence levels are not kept per group!")
+        }
+        (None, Some(level)) => match db.confidence_aggregation(level) {
+            Some(aggregation) => {
+                let aggregation = align(aggregation);
+                export_response(format, &aggregation, || to_rows(None, &aggregation))
+            }
+            None => HttpResponse::BadRequest().body(format!(
+                "Confidence level {} is not one of {:?}!",
+                level,
+                db.confidence_levels()
+            )),
+        },
+        (Some(key), None) => {
+            let mut groups = db.grouped_aggregation(key);
+            for group in groups.iter_mut() {
+                group.aggregation = align(std::mem::take(&mut group.aggregation));
+            }
+            export_response(format, &groups, || {
+                groups
+                    .iter()
+                    .flat_map(|group| to_rows(group.group.as_deref(), &group.aggregation))
+                    .collect()
+            })
+        }
+        (None, None) => {
+            let aggregation = align(db.aggregation());
+            export_response(format, &aggregation, || to_rows(None, &aggregation))
         }
     }
-    resample_series(&mut aggregation.series, 15);
-    serde_json::to_string(&aggregation)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ForecastQuery {
+    delivery_day: NaiveDate, // local delivery day
+    tz: Option<String>,      // market timezone, e.g. Europe/Berlin
+    period: Option<u32>,     // delivery period in minutes
+}
+
+#[utoipa::path(
+    params(ForecastQuery),
+    responses(
+        (status = 200, description = "Known, forecast and total envelope of the day", body = Forecast),
+        (status = 400, description = "Invalid timezone or period", body = String),
+    )
+)]
+#[get("/forecast")]
+pub async fn handle_forecast_request(
+    db: Data<Demands>,
+    query: web::Query<ForecastQuery>,
+) -> impl Responder {
+    let periods = match MarketPeriods::from_query(
+        query.tz.as_deref(),
+        query.period,
+        Some(query.delivery_day),
+    ) {
+        Ok(periods) => periods.unwrap(),
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let result = web::block(move || forecast(&db, &periods, Utc::now())).await;
+    match result {
+        Ok(Ok(forecast)) => HttpResponse::Ok().json(forecast),
+        Ok(Err(err)) => HttpResponse::BadRequest().body(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReserveQuery {
+    product: ReserveProduct,
+    duration: Option<i64>,   // minutes a full activation is held
+    block: Option<u32>,      // market block length in minutes
+    tz: Option<String>,      // timezone the market blocks are aligned in
+    confidence: Option<u32>, // confidence level of the envelope in percent
+}
+
+#[utoipa::path(
+    params(ReserveQuery),
+    responses(
+        (status = 200, description = "Reserve per market block", body = ReserveQualification),
+        (status = 400, description = "Invalid market block, duration or confidence level", body = String),
+    )
+)]
+#[get("/reserve")]
+pub async fn handle_reserve_request(
+    db: Data<Demands>,
+    query: web::Query<ReserveQuery>,
+) -> impl Responder {
+    let product = query.product;
+    let block = query.block.unwrap_or(product.default_block_minutes());
+    let periods = match MarketPeriods::new(query.tz.as_deref(), block, None) {
+        Ok(periods) => periods,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let aggregation = match query.confidence {
+        Some(level) => match db.confidence_aggregation(level) {
+            Some(aggregation) => aggregation,
+            None => {
+                return HttpResponse::BadRequest()
+                    .body(format!("Confidence level {} is not kept!", level))
+            }
+        },
+        None => db.aggregation(),
+    };
+    let duration = query.duration.unwrap_or(product.default_duration_minutes());
+    if duration < 1 {
+        return HttpResponse::BadRequest().body("duration must be at least one minute");
+    }
+    HttpResponse::Ok().json(qualify(&aggregation, product, duration, &periods))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SettlementQuery {
+    from: NaiveDate,                // first local day of arrival
+    to: Option<NaiveDate>,          // last local day of arrival, `from` if omitted
+    tz: Option<String>,             // timezone of the days, e.g. Europe/Berlin
+    level: Option<SettlementLevel>, // one row per session or per day
+    format: Option<ExportFormat>,
+}
+
+#[utoipa::path(
+    params(SettlementQuery),
+    responses(
+        (status = 200, description = "Settlement of the sessions that have ended", content(
+            (Vec<SessionSettlement> = "application/json"),
+            (Vec<DaySettlement> = "application/json"),
+            (String = "text/csv"),
+        )),
+        (status = 400, description = "Invalid timezone or format", body = String),
+    )
+)]
+#[get("/settlement")]
+pub async fn handle_settlement_request(
+    db: Data<Demands>,
+    query: web::Query<SettlementQuery>,
+    request: HttpRequest,
+) -> impl Responder {
+    let timezone: Tz = match &query.tz {
+        Some(name) => match name.parse() {
+            Ok(timezone) => timezone,
+            Err(_) => {
+                return HttpResponse::BadRequest().body(format!("Unknown timezone {}!", name))
+            }
+        },
+        None => Tz::UTC,
+    };
+    let format = negotiate_format(&request, query.format);
+    #[cfg(feature = "parquet")]
+    if format == ExportFormat::Parquet {
+        return HttpResponse::BadRequest().body("Settlement reports are JSON or CSV!");
+    }
+    let (from, to) = (query.from, query.to.unwrap_or(query.from));
+    let sessions = settle_sessions(&db, from, to, timezone, Utc::now());
+    let body = match query.level.unwrap_or_default() {
+        SettlementLevel::Session if format == ExportFormat::Csv => to_csv(&sessions),
+        SettlementLevel::Session => serde_json::to_vec(&sessions).map_err(std::io::Error::from),
+        SettlementLevel::Day if format == ExportFormat::Csv => to_csv(&settle_days(&sessions)),
+        SettlementLevel::Day => {
+            serde_json::to_vec(&settle_days(&sessions)).map_err(std::io::Error::from)
+        }
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[utoipa::path(
+    request_body = RecurringDemand,
+    responses(
+        (status = 200, description = "Template stored and expanded", body = String),
+        (status = 400, description = "Malformed or invalid template", body = String),
+    )
+)]
+#[post("/templates")]
+pub async fn handle_template(
+    db: Data<Demands>,
+    schedules: Data<Schedules>,
+    template: web::Json<RecurringDemand>,
+) -> impl Responder {
+    if let Err(err) = template.validate() {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let vehicle_id = template.vehicle_id.clone();
+    let message = match schedules.insert(&db, template.into_inner(), Utc::now()) {
+        Some(_) => format!("Updated template for {}!", vehicle_id),
+        None => format!("Received template for {}!", vehicle_id),
+    };
+    HttpResponse::Ok().body(message)
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "All recurring demands", body = Vec<RecurringDemand>),
+    )
+)]
+#[get("/templates")]
+pub async fn handle_template_list(schedules: Data<Schedules>) -> impl Responder {
+    HttpResponse::Ok().json(schedules.templates())
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Template and its sessions removed", body = String),
+        (status = 404, description = "No template for the vehicle", body = String),
+    )
+)]
+#[delete("/templates/{vehicle_id}")]
+pub async fn handle_template_removal(
+    db: Data<Demands>,
+    schedules: Data<Schedules>,
+    vehicle_id: web::Path<String>,
+) -> impl Responder {
+    match schedules.remove(&db, &vehicle_id) {
+        Some(_) => HttpResponse::Ok().body(format!("Removed template for {}!", vehicle_id)),
+        None => HttpResponse::NotFound().body(format!("No template for {}!", vehicle_id)),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SubscriptionInfo {
+    pub id: u64,
+    #[serde(flatten)]
+    pub subscription: Subscription,
+}
+
+#[utoipa::path(
+    request_body = Subscription,
+    responses(
+        (status = 201, description = "Subscription created", body = SubscriptionInfo),
+        (status = 400, description = "Invalid url or empty secret", body = String),
+    )
+)]
+#[post("/webhooks")]
+pub async fn handle_webhook_subscription(
+    webhooks: Data<Webhooks>,
+    subscription: web::Json<Subscription>,
+) -> impl Responder {
+    let subscription = subscription.into_inner();
+    if !["http://", "https://"]
+        .iter()
+        .any(|scheme| subscription.url.starts_with(scheme))
+    {
+        return HttpResponse::BadRequest().body("url must be an http or https url");
+    }
+    if subscription.secret.is_empty() {
+        return HttpResponse::BadRequest().body("secret must not be empty");
+    }
+    let id = webhooks.subscribe(subscription.clone());
+    HttpResponse::Created().json(SubscriptionInfo { id, subscription })
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "All subscriptions, without their secrets", body = Vec<SubscriptionInfo>),
+    )
+)]
+#[get("/webhooks")]
+pub async fn handle_webhook_list(webhooks: Data<Webhooks>) -> impl Responder {
+    let subscriptions: Vec<SubscriptionInfo> = webhooks
+        .subscriptions()
+        .into_iter()
+        .map(|(id, subscription)| SubscriptionInfo { id, subscription })
+        .collect();
+    HttpResponse::Ok().json(subscriptions)
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Subscription removed", body = String),
+        (status = 404, description = "No such subscription", body = String),
+    )
+)]
+#[delete("/webhooks/{id}")]
+pub async fn handle_webhook_removal(
+    webhooks: Data<Webhooks>,
+    id: web::Path<u64>,
+) -> impl Responder {
+    match webhooks.unsubscribe(*id) {
+        Some(_) => HttpResponse::Ok().body(format!("Removed webhook {}!", id)),
+        None => HttpResponse::NotFound().body(format!("No webhook {}!", id)),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Latest delivery attempts, oldest first", body = Vec<Delivery>),
+        (status = 404, description = "No such subscription", body = String),
+    )
+)]
+#[get("/webhooks/{id}/deliveries")]
+pub async fn handle_webhook_deliveries(
+    webhooks: Data<Webhooks>,
+    id: web::Path<u64>,
+) -> impl Responder {
+    match webhooks.deliveries(*id) {
+        Some(deliveries) => HttpResponse::Ok().json(deliveries),
+        None => HttpResponse::NotFound().body(format!("No webhook {}!", id)),
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "ev_flex",
+        description = "Charging flexibility of electric vehicle fleets"
+    ),
+    paths(
+        handle_energy_demand,
+        handle_bulk_demands,
+        handle_demands_export,
+        handle_demand_removal,
+        handle_vehicle_series_request,
+        handle_vehicle_envelope_request,
+        handle_soc_reading,
+        handle_fleet_policy,
+        handle_aggregation_request,
+        handle_reserve_request,
+        handle_forecast_request,
+        handle_settlement_request,
+        handle_template,
+        handle_template_list,
+        handle_template_removal,
+        handle_webhook_subscription,
+        handle_webhook_list,
+        handle_webhook_removal,
+        handle_webhook_deliveries,
+    ),
+    components(schemas(GroupAggregation, EventType))
+)]
+pub struct ApiDoc;
+
+/// Registers all endpoints, shared by the server and the tests.
+///
+/// The OpenAPI document is served at `/openapi.json` and browsable at `/swagger-ui/`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/openapi.json", ApiDoc::openapi()))
+        .service(handle_energy_demand)
+        .service(handle_demands_export)
+        // after the export, whose GET guard lets other methods through to here
+        .service(
+            web::resource("/demands")
+                .app_data(web::PayloadConfig::new(BULK_PAYLOAD_LIMIT))
+                .route(web::post().to(handle_bulk_demands)),
+        )
+        .service(handle_demand_removal)
+        .service(handle_vehicle_series_request)
+        .service(handle_vehicle_envelope_request)
+        .service(handle_soc_reading)
+        .service(handle_fleet_policy)
+        .service(handle_aggregation_request)
+        .service(handle_reserve_request)
+        .service(handle_forecast_request)
+        .service(handle_settlement_request)
+        .service(handle_template)
+        .service(handle_template_list)
+        .service(handle_template_removal)
+        .service(handle_webhook_subscription)
+        .service(handle_webhook_list)
+        .service(handle_webhook_removal)
+        .service(handle_webhook_deliveries);
 }
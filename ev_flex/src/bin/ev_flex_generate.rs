@@ -0,0 +1,77 @@
+use chrono::NaiveDate;
+use clap::Parser;
+use ev_flex::{generate_fleet, FleetConfig};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Generates a synthetic fleet of energy demands as JSON lines or CSV.
+#[derive(Parser)]
+struct Args {
+    /// Number of vehicles
+    #[arg(long, default_value_t = 1000)]
+    vehicles: usize,
+    /// Seed of the random generator, the same seed yields the same fleet
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Day of arrival (UTC)
+    #[arg(long, default_value = "2023-05-01")]
+    day: NaiveDate,
+    /// Mean arrival time in hours after midnight UTC
+    #[arg(long, default_value_t = 18.0)]
+    arrival_mean: f64,
+    /// Mean plugged-in time in hours
+    #[arg(long, default_value_t = 12.0)]
+    dwell_mean: f64,
+    /// Mean state of charge on arrival in percent
+    #[arg(long, default_value_t = 35.0)]
+    soc_mean: f64,
+    /// Number of fleets the vehicles are spread across
+    #[arg(long, default_value_t = 1)]
+    fleets: usize,
+    /// Number of grid nodes the vehicles are spread across
+    #[arg(long, default_value_t = 1)]
+    grid_nodes: usize,
+    /// Write CSV instead of JSON lines
+    #[arg(long)]
+    csv: bool,
+    /// Output file, stdout if omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let config = FleetConfig {
+        vehicles: args.vehicles,
+        seed: args.seed,
+        day: args.day,
+        arrival_mean_hours: args.arrival_mean,
+        dwell_mean_hours: args.dwell_mean,
+        current_soc_mean: args.soc_mean,
+        fleets: args.fleets,
+        grid_nodes: args.grid_nodes,
+        ..Default::default()
+    };
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    let demands = generate_fleet(&config);
+    if args.csv {
+        let mut writer = csv::Writer::from_writer(output);
+        for demand in &demands {
+            writer.serialize(demand)?;
+        }
+        writer.flush()?;
+    } else {
+        let mut output = output;
+        for demand in &demands {
+            serde_json::to_writer(&mut output, demand)?;
+            writeln!(output)?;
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
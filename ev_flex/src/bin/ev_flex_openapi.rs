@@ -0,0 +1,7 @@
+use ev_flex::api::ApiDoc;
+use utoipa::OpenApi;
+
+/// Prints the OpenAPI document of the server, e.g. for generating clients.
+fn main() {
+    println!("{}", ApiDoc::openapi().to_pretty_json().unwrap());
+}
@@ -0,0 +1,39 @@
+use chrono::Duration;
+use clap::Parser;
+use ev_flex::{read_demands, Replay, ReplayStatistics};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// Replays recorded energy demands through the aggregation offline.
+#[derive(Parser)]
+struct Args {
+    /// Recorded demands, as JSON lines or CSV (by `.csv` extension)
+    input: PathBuf,
+    /// Simulated clock step in minutes
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(i64).range(1..))]
+    step: i64,
+    /// Where to write one JSON line per step with the fleet envelope, stdout if omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let demands = read_demands(&args.input)?;
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    let replay = Replay::new(demands, Duration::minutes(args.step));
+    let mut statistics = ReplayStatistics::new(&replay);
+    for step in replay {
+        statistics.record(&step);
+        serde_json::to_writer(&mut output, &step)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    eprintln!("{}", serde_json::to_string_pretty(&statistics)?);
+    Ok(())
+}
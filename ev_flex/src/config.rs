@@ -0,0 +1,155 @@
+use crate::demand::DEFAULT_CONFIDENCE_LEVELS;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Server settings, read from `EV_FLEX_*` environment variables.
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub confidence_levels: Vec<u32>, // confidence levels kept in percent
+    pub mqtt: Option<MqttConfig>,    // MQTT ingestion, if `EV_FLEX_MQTT_HOST` is set
+    pub webhooks: WebhookConfig,
+    pub schedule_horizon_hours: u32, // how far ahead recurring demands are expanded
+    pub snapshot_path: Option<PathBuf>, // file the store is restored from and flushed to
+    pub shutdown_timeout_secs: u64,  // time in-flight requests get to finish on shutdown
+}
+
+/// Broker and topics of MQTT ingestion.
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub demand_topics: Vec<String>, // topic filters demands are published on
+    pub aggregation_topic: Option<String>, // topic the fleet envelope is published on
+}
+
+impl MqttConfig {
+    pub fn new(host: &str, port: u16) -> Self {
+        MqttConfig {
+            host: host.to_string(),
+            port,
+            client_id: "ev_flex".to_string(),
+            demand_topics: vec!["ev_flex/demand".to_string()],
+            aggregation_topic: Some("ev_flex/aggregation".to_string()),
+        }
+    }
+
+    fn from_env() -> Result<Option<Self>, String> {
+        let Ok(host) = env::var("EV_FLEX_MQTT_HOST") else {
+            return Ok(None);
+        };
+        let default = MqttConfig::new(&host, parse("EV_FLEX_MQTT_PORT")?.unwrap_or(1883));
+        let aggregation_topic = match env::var("EV_FLEX_MQTT_AGGREGATION_TOPIC") {
+            Ok(topic) if topic.trim().is_empty() => None,
+            Ok(topic) => Some(topic),
+            Err(_) => default.aggregation_topic,
+        };
+        Ok(Some(MqttConfig {
+            client_id: env::var("EV_FLEX_MQTT_CLIENT_ID").unwrap_or(default.client_id),
+            demand_topics: parse_list("EV_FLEX_MQTT_DEMAND_TOPICS")?
+                .unwrap_or(default.demand_topics),
+            aggregation_topic,
+            ..default
+        }))
+    }
+}
+
+/// Delivery of webhook notifications.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub retry_delays: Vec<Duration>, // delays before the retries of a failed delivery
+    pub timeout: Duration,
+    pub aggregation_threshold: f64, // change of the fleet envelope in percent that is notified
+    pub expiry_interval: Duration,  // how often expired demands are looked for
+    pub log_size: usize,            // deliveries kept per subscription
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            retry_delays: [1, 5, 30, 120, 600].map(Duration::from_secs).to_vec(),
+            timeout: Duration::from_secs(10),
+            aggregation_threshold: 5.0,
+            expiry_interval: Duration::from_secs(60),
+            log_size: 100,
+        }
+    }
+}
+
+impl WebhookConfig {
+    fn from_env() -> Result<Self, String> {
+        let default = WebhookConfig::default();
+        let retry_delays = parse_list("EV_FLEX_WEBHOOK_RETRY_DELAYS")?
+            .map(|delays: Vec<u64>| delays.into_iter().map(Duration::from_secs).collect());
+        Ok(WebhookConfig {
+            retry_delays: retry_delays.unwrap_or(default.retry_delays),
+            aggregation_threshold: parse("EV_FLEX_WEBHOOK_THRESHOLD")?
+                .unwrap_or(default.aggregation_threshold),
+            ..default
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            confidence_levels: DEFAULT_CONFIDENCE_LEVELS.to_vec(),
+            mqtt: None,
+            webhooks: WebhookConfig::default(),
+            schedule_horizon_hours: 48,
+            snapshot_path: None,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid value {} for {}!", value, name)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(name: &str) -> Result<Option<Vec<T>>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| item.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Some)
+            .map_err(|_| format!("Invalid value {} for {}!", value, name)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let default = Config::default();
+        let confidence_levels: Vec<u32> =
+            parse_list("EV_FLEX_CONFIDENCE_LEVELS")?.unwrap_or(default.confidence_levels);
+        if confidence_levels.iter().any(|level| *level > 100) {
+            return Err("Confidence levels must be percentages!".to_string());
+        }
+        Ok(Config {
+            host: env::var("EV_FLEX_HOST").unwrap_or(default.host),
+            port: parse("EV_FLEX_PORT")?.unwrap_or(default.port),
+            confidence_levels,
+            mqtt: MqttConfig::from_env()?,
+            webhooks: WebhookConfig::from_env()?,
+            schedule_horizon_hours: parse("EV_FLEX_SCHEDULE_HORIZON_HOURS")?
+                .unwrap_or(default.schedule_horizon_hours),
+            snapshot_path: env::var_os("EV_FLEX_SNAPSHOT_PATH").map(PathBuf::from),
+            shutdown_timeout_secs: parse("EV_FLEX_SHUTDOWN_TIMEOUT")?
+                .unwrap_or(default.shutdown_timeout_secs),
+        })
+    }
+}
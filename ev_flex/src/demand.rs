@@ -1,8 +1,20 @@
+use crate::aggregation::{
+    vehicle_aggregation, Aggregation, Contribution, Envelopes, GroupAggregation,
+};
+use crate::departure::DepartureProbability;
+use crate::envelope::VehicleEnvelope;
+use crate::policy::DegradationPolicy;
 use chrono::prelude::{DateTime, Utc};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, RwLock};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct EnergyDemand {
     pub vehicle_id: String,
     pub min_soc: i32,            // minimum state of charge in percent
@@ -13,20 +25,484 @@ pub struct EnergyDemand {
     pub max_charging_power: i32, // maximum charging power in W
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    // optional grouping attributes used by grouped aggregation
+    pub fleet: Option<String>,
+    pub charger_type: Option<String>,
+    pub grid_node: Option<String>,
+    pub customer: Option<String>,
+    // battery protection limits, overriding the policy of the fleet
+    pub degradation_policy: Option<DegradationPolicy>,
+    // cumulative probability of leaving before `end`
+    pub departure_distribution: Option<Vec<DepartureProbability>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKey {
+    Fleet,
+    ChargerType,
+    GridNode,
+    Customer,
+}
+
+impl GroupKey {
+    pub const ALL: [GroupKey; 4] = [
+        GroupKey::Fleet,
+        GroupKey::ChargerType,
+        GroupKey::GridNode,
+        GroupKey::Customer,
+    ];
+}
+
+pub type Group = (GroupKey, Option<String>);
+
+impl EnergyDemand {
+    pub fn group(&self, key: GroupKey) -> Option<&String> {
+        match key {
+            GroupKey::Fleet => self.fleet.as_ref(),
+            GroupKey::ChargerType => self.charger_type.as_ref(),
+            GroupKey::GridNode => self.grid_node.as_ref(),
+            GroupKey::Customer => self.customer.as_ref(),
+        }
+    }
+
+    /// Every group this demand belongs to, one per grouping key.
+    pub fn groups(&self) -> Vec<Group> {
+        GroupKey::ALL
+            .iter()
+            .map(|key| (*key, self.group(*key).cloned()))
+            .collect()
+    }
+
+    /// Checks the demand for values no vehicle can have.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.vehicle_id.is_empty() {
+            return Err("vehicle_id must not be empty".to_string());
+        }
+        // reserved for the sessions expanded from recurring demands
+        if self.vehicle_id.contains('@') {
+            return Err("vehicle_id must not contain @".to_string());
+        }
+        let socs = [
+            self.min_soc,
+            self.target_soc,
+            self.max_soc,
+            self.current_soc,
+        ];
+        if socs.iter().any(|soc| !(0..=100).contains(soc)) {
+            return Err("states of charge must be between 0 and 100 percent".to_string());
+        }
+        if self.min_soc > self.target_soc || self.target_soc > self.max_soc {
+            return Err("expected min_soc <= target_soc <= max_soc".to_string());
+        }
+        if self.capacity <= 0 || self.max_charging_power <= 0 {
+            return Err("capacity and max_charging_power must be positive".to_string());
+        }
+        if self.start >= self.end {
+            return Err("start must be before end".to_string());
+        }
+        if let Some(policy) = &self.degradation_policy {
+            policy.validate()?;
+            if policy.comfort_max_soc.is_some_and(|max| max < self.min_soc) {
+                return Err("comfort_max_soc must not be below min_soc".to_string());
+            }
+        }
+        self.validate_departure_distribution()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl EnergyDemand {
+    /// Overnight session from 18:00 on 2023-05-01 to 06:00 UTC, charging a
+    /// 60 kWh battery at 11 kW from 10 to 80 percent, for tests to adapt.
+    pub fn example(vehicle_id: &str) -> Self {
+        use chrono::TimeZone;
+        EnergyDemand::example_session(
+            vehicle_id,
+            Utc.with_ymd_and_hms(2023, 5, 1, 18, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2023, 5, 2, 6, 0, 0).unwrap(),
+        )
+    }
+
+    /// The [`example`](EnergyDemand::example) demand for a session from
+    /// `start` to `end`.
+    pub fn example_session(vehicle_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        EnergyDemand {
+            vehicle_id: vehicle_id.to_string(),
+            min_soc: 20,
+            max_soc: 100,
+            target_soc: 80,
+            current_soc: 10,
+            capacity: 60000,
+            max_charging_power: 11000,
+            start,
+            end,
+            fleet: None,
+            charger_type: None,
+            grid_node: None,
+            customer: None,
+            degradation_policy: None,
+            departure_distribution: None,
+        }
+    }
+}
+
+/// State of charge measured at the charger or reported by the vehicle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SocReading {
+    #[serde(default = "Utc::now")]
+    pub time: DateTime<Utc>,
+    pub soc: i32, // state of charge in percent
+}
+
+/// Change of the stored demands, sent to the listeners in the order of the changes.
+#[derive(Clone, Debug)]
+pub enum DemandEvent {
+    Created(EnergyDemand),
+    Updated(EnergyDemand),
+    Deleted(String),
+    // the demand stored for the vehicle cannot reach its target in time
+    Infeasible(String),
 }
 
 pub struct Demands {
-    pub demands: Mutex<Vec<EnergyDemand>>,
+    // readers never block each other; writers take `demands` before `envelope`
+    pub(crate) demands: RwLock<HashMap<String, EnergyDemand>>,
+    pub(crate) envelope: RwLock<Envelopes>,
+    pub(crate) fleet_policies: RwLock<HashMap<String, DegradationPolicy>>,
+    // policy the envelope of the stored demand of a vehicle was last published with
+    pub(crate) published_policies: RwLock<HashMap<String, DegradationPolicy>>,
+    confidence_levels: Vec<u32>,
+    listeners: Mutex<Vec<Sender<DemandEvent>>>,
+    // sessions replaced by a later session of the same vehicle, oldest first
+    pub(crate) history: Mutex<VecDeque<EnergyDemand>>,
+    // measured states of charge per vehicle, across its sessions
+    pub(crate) readings: RwLock<HashMap<String, VecDeque<SocReading>>>,
 }
 
+impl Default for Demands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type Prepared = (Option<DegradationPolicy>, Option<Contribution>);
+
+pub const DEFAULT_CONFIDENCE_LEVELS: [u32; 2] = [50, 90];
+
+/// Number of past sessions kept for forecasting and settlement.
+pub const HISTORY_SIZE: usize = 10_000;
+
+/// Number of state of charge readings kept per vehicle.
+pub const READINGS_SIZE: usize = 1_000;
+
 impl Demands {
     pub fn new() -> Self {
-        let demands = Mutex::new(vec![]);
-        Demands { demands }
+        Self::with_confidence_levels(&DEFAULT_CONFIDENCE_LEVELS)
+    }
+
+    /// Store keeping envelopes for the given confidence levels in percent.
+    pub fn with_confidence_levels(confidence_levels: &[u32]) -> Self {
+        let demands = RwLock::new(HashMap::new());
+        let envelope = RwLock::new(Envelopes::new(confidence_levels));
+        let fleet_policies = RwLock::new(HashMap::new());
+        Demands {
+            demands,
+            envelope,
+            fleet_policies,
+            published_policies: RwLock::default(),
+            confidence_levels: confidence_levels.to_vec(),
+            listeners: Mutex::default(),
+            history: Mutex::default(),
+            readings: RwLock::default(),
+        }
+    }
+
+    /// The demand with the policy of its fleet, unless it has its own.
+    pub(crate) fn with_policy<'a>(&self, demand: &'a EnergyDemand) -> Cow<'a, EnergyDemand> {
+        let fleet_policy = match (&demand.degradation_policy, &demand.fleet) {
+            (None, Some(fleet)) => self.fleet_policy(fleet),
+            _ => None,
+        };
+        match fleet_policy {
+            Some(policy) => Cow::Owned(EnergyDemand {
+                degradation_policy: Some(policy),
+                ..demand.clone()
+            }),
+            None => Cow::Borrowed(demand),
+        }
+    }
+
+    /// The stored demand with the policy its envelope was last published with,
+    /// at submission or when its fleet policy changed since.
+    pub(crate) fn published<'a>(&self, demand: &'a EnergyDemand) -> Cow<'a, EnergyDemand> {
+        let policies = self.published_policies.read().unwrap();
+        match policies.get(&demand.vehicle_id) {
+            Some(policy) if demand.degradation_policy.is_none() => Cow::Owned(EnergyDemand {
+                degradation_policy: Some(policy.clone()),
+                ..demand.clone()
+            }),
+            _ => Cow::Borrowed(demand),
+        }
+    }
+
+    fn contribution(&self, demand: &EnergyDemand) -> Option<Contribution> {
+        let envelope = VehicleEnvelope::from_demand(demand)?;
+        let confidence = match demand.departure_distribution {
+            Some(_) => self
+                .confidence_levels
+                .iter()
+                .map(|level| {
+                    let envelope = VehicleEnvelope::from_demand(&demand.at_confidence(*level));
+                    (*level, envelope)
+                })
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        Some(Contribution {
+            groups: demand.groups(),
+            envelope,
+            confidence,
+        })
+    }
+
+    /// Sends every later change of the store to `listener`, next to the
+    /// listeners added before.
+    pub fn add_listener(&self, listener: Sender<DemandEvent>) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    /// Stops notifying, so that the receivers of all listeners disconnect.
+    pub fn remove_listeners(&self) {
+        self.listeners.lock().unwrap().clear();
+    }
+
+    fn notify(&self, event: DemandEvent) {
+        // listeners that have gone away are dropped
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.send(event.clone()).is_ok());
+    }
+
+    // contribution of the demand under the current fleet policy, with that policy
+    fn prepare(&self, demand: &EnergyDemand) -> Prepared {
+        let effective = self.with_policy(demand);
+        (
+            effective.degradation_policy.clone(),
+            self.contribution(&effective),
+        )
+    }
+
+    // stores a prepared demand, the caller holds both write locks
+    fn commit(
+        &self,
+        demands: &mut HashMap<String, EnergyDemand>,
+        envelope: &mut Envelopes,
+        demand: EnergyDemand,
+        (policy, mut contribution): Prepared,
+    ) -> Option<EnergyDemand> {
+        // the fleet policy may have changed while the series was computed
+        let effective = self.with_policy(&demand);
+        if effective.degradation_policy != policy {
+            contribution = self.contribution(&effective);
+        }
+        let vehicle_id = demand.vehicle_id.clone();
+        let feasible = contribution.is_some();
+        let previous = demands.insert(vehicle_id.clone(), demand.clone());
+        envelope.set_vehicle(&vehicle_id, contribution);
+        let mut published_policies = self.published_policies.write().unwrap();
+        let previous_policy = match effective.degradation_policy.clone() {
+            Some(policy) => published_policies.insert(vehicle_id.clone(), policy),
+            None => published_policies.remove(&vehicle_id),
+        };
+        drop(published_policies);
+        if let Some(previous) = previous.as_ref().filter(|p| p.end <= demand.start) {
+            // the history keeps sessions as they were published
+            let mut history = self.history.lock().unwrap();
+            history.push_back(EnergyDemand {
+                degradation_policy: previous_policy,
+                ..previous.clone()
+            });
+            if history.len() > HISTORY_SIZE {
+                history.pop_front();
+            }
+        }
+        self.notify(match previous {
+            Some(_) => DemandEvent::Updated(demand),
+            None => DemandEvent::Created(demand),
+        });
+        if !feasible {
+            self.notify(DemandEvent::Infeasible(vehicle_id));
+        }
+        previous
+    }
+
+    /// Stores the demand, replacing any previous demand of the same vehicle.
+    pub fn insert(&self, demand: EnergyDemand) -> Option<EnergyDemand> {
+        let prepared = self.prepare(&demand);
+        let mut demands = self.demands.write().unwrap();
+        let mut envelope = self.envelope.write().unwrap();
+        self.commit(&mut demands, &mut envelope, demand, prepared)
+    }
+
+    /// Stores many demands as if inserted one after another, with their
+    /// series computed in parallel with the `parallel` feature.
+    pub fn insert_many(&self, new_demands: Vec<EnergyDemand>) -> Vec<Option<EnergyDemand>> {
+        #[cfg(feature = "parallel")]
+        let iter = new_demands.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let iter = new_demands.iter();
+        let prepared: Vec<Prepared> = iter.map(|demand| self.prepare(demand)).collect();
+        let mut demands = self.demands.write().unwrap();
+        let mut envelope = self.envelope.write().unwrap();
+        new_demands
+            .into_iter()
+            .zip(prepared)
+            .map(|(demand, prepared)| self.commit(&mut demands, &mut envelope, demand, prepared))
+            .collect()
+    }
+
+    /// Removes the demand of the vehicle together with its readings.
+    pub fn remove(&self, vehicle_id: &str) -> Option<EnergyDemand> {
+        let mut demands = self.demands.write().unwrap();
+        let previous = demands.remove(vehicle_id);
+        self.envelope.write().unwrap().set_vehicle(vehicle_id, None);
+        self.published_policies.write().unwrap().remove(vehicle_id);
+        self.readings.write().unwrap().remove(vehicle_id);
+        if previous.is_some() {
+            self.notify(DemandEvent::Deleted(vehicle_id.to_string()));
+        }
+        previous
+    }
+
+    /// Past sessions that a later session of the same vehicle replaced,
+    /// oldest first and at most `HISTORY_SIZE`.
+    pub fn history(&self) -> Vec<EnergyDemand> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Records a measured state of charge, false if the vehicle is unknown.
+    pub fn add_reading(&self, vehicle_id: &str, reading: SocReading) -> bool {
+        if !self.contains(vehicle_id) {
+            return false;
+        }
+        let mut readings = self.readings.write().unwrap();
+        let readings = readings.entry(vehicle_id.to_string()).or_default();
+        let index = readings.partition_point(|r| r.time <= reading.time);
+        readings.insert(index, reading);
+        if readings.len() > READINGS_SIZE {
+            readings.pop_front();
+        }
+        true
+    }
+
+    /// Measured states of charge of the vehicle in time order.
+    pub fn readings(&self, vehicle_id: &str) -> Vec<SocReading> {
+        let readings = self.readings.read().unwrap();
+        readings
+            .get(vehicle_id)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// The demand stored for the vehicle.
+    pub fn get(&self, vehicle_id: &str) -> Option<EnergyDemand> {
+        self.demands.read().unwrap().get(vehicle_id).cloned()
+    }
+
+    pub fn contains(&self, vehicle_id: &str) -> bool {
+        self.demands.read().unwrap().contains_key(vehicle_id)
+    }
+
+    /// Number of stored demands.
+    pub fn len(&self) -> usize {
+        self.demands.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.demands.read().unwrap().is_empty()
+    }
+
+    /// Vehicles with a stored demand, in order.
+    pub fn vehicle_ids(&self) -> Vec<String> {
+        let mut vehicle_ids: Vec<String> = self.demands.read().unwrap().keys().cloned().collect();
+        vehicle_ids.sort();
+        vehicle_ids
+    }
+
+    /// All stored demands ordered by vehicle.
+    pub fn all(&self) -> Vec<EnergyDemand> {
+        let mut demands: Vec<EnergyDemand> =
+            self.demands.read().unwrap().values().cloned().collect();
+        demands.sort_by(|a, b| a.vehicle_id.cmp(&b.vehicle_id));
+        demands
+    }
+
+    pub fn fleet_policy(&self, fleet: &str) -> Option<DegradationPolicy> {
+        self.fleet_policies.read().unwrap().get(fleet).cloned()
+    }
+
+    /// Sets the policy of a fleet and updates the envelopes of its vehicles.
+    #[allow(clippy::readonly_write_lock)]
+    pub fn set_fleet_policy(&self, fleet: &str, policy: DegradationPolicy) {
+        // exclusive, so that concurrent policy updates apply in the same order
+        // to the policies and the envelopes
+        let demands = self.demands.write().unwrap();
+        self.fleet_policies
+            .write()
+            .unwrap()
+            .insert(fleet.to_string(), policy.clone());
+        #[cfg(feature = "parallel")]
+        let iter = demands.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let iter = demands.iter();
+        let updates: Vec<(&String, Option<Contribution>)> = iter
+            .filter(|(_, demand)| demand.fleet.as_deref() == Some(fleet))
+            .filter(|(_, demand)| demand.degradation_policy.is_none())
+            .map(|(vehicle_id, demand)| (vehicle_id, self.contribution(&self.with_policy(demand))))
+            .collect();
+        let mut envelope = self.envelope.write().unwrap();
+        let mut published_policies = self.published_policies.write().unwrap();
+        for (vehicle_id, contribution) in updates {
+            if contribution.is_none() {
+                self.notify(DemandEvent::Infeasible(vehicle_id.clone()));
+            }
+            envelope.set_vehicle(vehicle_id, contribution);
+            // the envelope is republished under the new policy
+            published_policies.insert(vehicle_id.clone(), policy.clone());
+        }
+    }
+
+    pub fn aggregation(&self) -> Aggregation {
+        self.envelope.read().unwrap().total.to_aggregation()
+    }
+
+    pub fn confidence_levels(&self) -> &[u32] {
+        &self.confidence_levels
+    }
+
+    /// Fleet envelope that holds with `level` percent confidence, if that level is kept.
+    pub fn confidence_aggregation(&self, level: u32) -> Option<Aggregation> {
+        self.envelope.read().unwrap().at_confidence(level)
+    }
+
+    pub fn vehicle_aggregation(&self, vehicle_id: &str) -> Option<Aggregation> {
+        let demand = self.get(vehicle_id)?;
+        Some(vehicle_aggregation(&self.with_policy(&demand)))
+    }
+
+    /// Breakpoints of the envelope of a vehicle, None if unknown or infeasible.
+    pub fn vehicle_envelope(&self, vehicle_id: &str) -> Option<VehicleEnvelope> {
+        let demand = self.get(vehicle_id)?;
+        VehicleEnvelope::from_demand(&self.with_policy(&demand))
     }
 
-    pub fn insert(&self, demand: EnergyDemand) {
-        let mut demands = self.demands.lock().unwrap();
-        demands.push(demand);
+    pub fn grouped_aggregation(&self, key: GroupKey) -> Vec<GroupAggregation> {
+        self.envelope.read().unwrap().grouped(key)
     }
 }
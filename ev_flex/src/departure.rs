@@ -0,0 +1,154 @@
+use crate::demand::EnergyDemand;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Point of the cumulative departure distribution: the vehicle has left by
+/// `time` with `probability`.
+///
+/// The distribution is linear between points, starts at zero at the session
+/// start and reaches one at the session end. Without any points the vehicle
+/// leaves at the end.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DepartureProbability {
+    pub time: DateTime<Utc>,
+    pub probability: f64,
+}
+
+impl EnergyDemand {
+    // a cumulative distribution rises over strictly increasing times
+    pub(crate) fn validate_departure_distribution(&self) -> Result<(), String> {
+        let Some(distribution) = &self.departure_distribution else {
+            return Ok(());
+        };
+        if distribution
+            .iter()
+            .any(|point| !(0.0..=1.0).contains(&point.probability))
+        {
+            return Err("departure probabilities must be between 0 and 1".to_string());
+        }
+        for window in distribution.windows(2) {
+            if window[0].time >= window[1].time {
+                return Err("departure times must be strictly increasing".to_string());
+            }
+            if window[0].probability > window[1].probability {
+                return Err("departure probabilities must not decrease".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // cumulative distribution points including the session bounds
+    fn departure_points(&self) -> Vec<(DateTime<Utc>, f64)> {
+        let mut points = vec![(self.start, 0.0)];
+        for point in self.departure_distribution.iter().flatten() {
+            let (_, last_probability) = points[points.len() - 1];
+            if point.time > self.start && point.time < self.end {
+                points.push((point.time, point.probability.clamp(last_probability, 1.0)));
+            }
+        }
+        points.push((self.end, 1.0));
+        points
+    }
+
+    /// Time until which the vehicle is still plugged in with `confidence` percent.
+    pub fn departure_at_confidence(&self, confidence: u32) -> DateTime<Utc> {
+        if self.departure_distribution.is_none() {
+            return self.end;
+        }
+        let quantile = 1.0 - confidence.min(100) as f64 / 100.0;
+        let points = self.departure_points();
+        for window in points.windows(2) {
+            let ((from, p_from), (to, p_to)) = (window[0], window[1]);
+            if quantile < p_to {
+                if p_to <= p_from || quantile <= p_from {
+                    return from;
+                }
+                let share = (quantile - p_from) / (p_to - p_from);
+                let seconds = ((to - from).num_seconds() as f64 * share).round() as i64;
+                return from + Duration::seconds(seconds);
+            }
+        }
+        self.end
+    }
+
+    /// The demand as it can be relied on with `confidence` percent.
+    ///
+    /// The session ends at the departure quantile and the target is capped to
+    /// what can still be charged until then.
+    pub fn at_confidence(&self, confidence: u32) -> EnergyDemand {
+        let end = self.departure_at_confidence(confidence);
+        let one_percent_energy = (self.capacity / 100).max(1) as i64;
+        let chargeable = (self.max_charging_power / 60) as i64 * (end - self.start).num_minutes();
+        let reachable_soc = self.current_soc as i64 + chargeable / one_percent_energy;
+        EnergyDemand {
+            end,
+            target_soc: self.target_soc.min(reachable_soc.min(100) as i32),
+            departure_distribution: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::vehicle_series;
+    use crate::demand::EnergyDemand;
+    use crate::departure::DepartureProbability;
+    use chrono::{TimeZone, Utc};
+
+    fn demand() -> EnergyDemand {
+        EnergyDemand {
+            departure_distribution: Some(vec![
+                DepartureProbability {
+                    time: Utc.with_ymd_and_hms(2023, 5, 1, 19, 0, 0).unwrap(),
+                    probability: 0.0,
+                },
+                DepartureProbability {
+                    time: Utc.with_ymd_and_hms(2023, 5, 1, 20, 0, 0).unwrap(),
+                    probability: 0.2,
+                },
+            ]),
+            ..EnergyDemand::example("a")
+        }
+    }
+
+    #[test]
+    fn test_departure_at_confidence() {
+        let demand = demand();
+        let at = |h, m| Utc.with_ymd_and_hms(2023, 5, 1, h, m, 0).unwrap();
+        assert_eq!(demand.departure_at_confidence(100), at(19, 0));
+        assert_eq!(demand.departure_at_confidence(90), at(19, 30));
+        assert_eq!(demand.departure_at_confidence(80), at(20, 0));
+        assert_eq!(demand.departure_at_confidence(0), demand.end);
+
+        let without_distribution = EnergyDemand {
+            departure_distribution: None,
+            ..demand.clone()
+        };
+        assert_eq!(without_distribution.departure_at_confidence(90), demand.end);
+    }
+
+    #[test]
+    fn test_invalid_departure_distribution() {
+        assert!(demand().validate().is_ok());
+        let invalid = |change: fn(&mut Vec<DepartureProbability>)| {
+            let mut demand = demand();
+            change(demand.departure_distribution.as_mut().unwrap());
+            demand.validate().is_err()
+        };
+        assert!(invalid(|points| points.reverse()));
+        assert!(invalid(|points| points[1].time = points[0].time));
+        assert!(invalid(|points| points[1].probability = f64::NAN));
+        assert!(invalid(|points| points[1].probability = 1.5));
+        assert!(invalid(|points| points[0].probability = 0.5));
+    }
+
+    #[test]
+    fn test_at_confidence_stays_feasible() {
+        let demand = demand().at_confidence(90);
+        assert!(demand.target_soc < 80);
+        let series = vehicle_series(&demand).unwrap();
+        assert_eq!(series.last().unwrap().time, demand.end);
+    }
+}
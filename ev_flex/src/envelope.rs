@@ -0,0 +1,575 @@
+use crate::aggregation::AggregationDT;
+use crate::demand::EnergyDemand;
+use crate::utils::TimeRange;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Breakpoint {
+    pub time: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Function of time, linear between its breakpoints and only defined from
+/// the first to the last one.
+///
+/// Up to three breakpoints may share a time to describe a jump: the first is
+/// the value approached from the left, the last the one approached from the
+/// right and the second the value at that time.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(try_from = "Vec<Breakpoint>", into = "Vec<Breakpoint>")]
+pub struct PiecewiseLinear {
+    points: Vec<Breakpoint>,
+}
+
+impl TryFrom<Vec<Breakpoint>> for PiecewiseLinear {
+    type Error = String;
+
+    fn try_from(points: Vec<Breakpoint>) -> Result<Self, Self::Error> {
+        Self::new(points)
+    }
+}
+
+impl From<PiecewiseLinear> for Vec<Breakpoint> {
+    fn from(function: PiecewiseLinear) -> Self {
+        function.points
+    }
+}
+
+// values left of, at and right of a breakpoint time
+#[derive(Clone, Copy)]
+struct Knot {
+    time: DateTime<Utc>,
+    left: f64,
+    value: f64,
+    right: f64,
+}
+
+fn seconds(duration: Duration) -> f64 {
+    match duration.num_nanoseconds() {
+        Some(nanoseconds) => nanoseconds as f64 / 1e9,
+        None => duration.num_milliseconds() as f64 / 1e3,
+    }
+}
+
+fn after(time: DateTime<Utc>, seconds: f64) -> DateTime<Utc> {
+    time + Duration::nanoseconds((seconds * 1e9).round() as i64)
+}
+
+// equal up to the rounding of summing and subtracting large values
+fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-6 + 1e-12 * a.abs().max(b.abs())
+}
+
+// the values at `time` of a function given by its knots, zero outside of its
+// range; `next` is the first knot not before `time` and moves past a knot at
+// `time`
+fn knot_at(knots: &[Knot], next: &mut usize, time: DateTime<Utc>) -> Knot {
+    match knots.get(*next) {
+        Some(knot) if knot.time == time => {
+            *next += 1;
+            Knot {
+                left: if *next == 1 { 0.0 } else { knot.left },
+                right: if *next == knots.len() {
+                    0.0
+                } else {
+                    knot.right
+                },
+                ..*knot
+            }
+        }
+        Some(knot) if *next > 0 => {
+            let previous = &knots[*next - 1];
+            let share = seconds(time - previous.time) / seconds(knot.time - previous.time);
+            let value = previous.right + (knot.left - previous.right) * share;
+            Knot {
+                time,
+                left: value,
+                value,
+                right: value,
+            }
+        }
+        _ => Knot {
+            time,
+            left: 0.0,
+            value: 0.0,
+            right: 0.0,
+        },
+    }
+}
+
+// the breakpoints grouped by time
+fn knots_of(points: &[Breakpoint]) -> Vec<Knot> {
+    points
+        .chunk_by(|a, b| a.time == b.time)
+        .map(|group| Knot {
+            time: group[0].time,
+            left: group[0].value,
+            value: group[1.min(group.len() - 1)].value,
+            right: group[group.len() - 1].value,
+        })
+        .collect()
+}
+
+fn push_knot(points: &mut Vec<Breakpoint>, knot: Knot) {
+    let breakpoint = |value| Breakpoint {
+        time: knot.time,
+        value,
+    };
+    if knot.left != knot.value || knot.right != knot.value {
+        points.push(breakpoint(knot.left));
+    }
+    points.push(breakpoint(knot.value));
+    if knot.right != knot.value {
+        points.push(breakpoint(knot.right));
+    }
+}
+
+// drops jumps of no height and knots that lie on the line between their
+// neighbours, looking only at knots where `moved` holds for one of the three
+fn simplify(knots: Vec<Knot>, moved: impl Fn(usize) -> bool) -> Vec<Knot> {
+    let mut simplified: Vec<(Knot, bool)> = Vec::with_capacity(knots.len());
+    for (i, mut knot) in knots.into_iter().enumerate() {
+        if close(knot.left, knot.value) {
+            knot.left = knot.value;
+        }
+        if close(knot.right, knot.value) {
+            knot.right = knot.value;
+        }
+        let knot_moved = moved(i);
+        if let [.., (a, a_moved), (b, b_moved)] = simplified[..] {
+            let continuous = b.left == b.value && b.right == b.value;
+            if continuous && (a_moved || b_moved || knot_moved) {
+                let share = seconds(b.time - a.time) / seconds(knot.time - a.time);
+                if close(a.right + (knot.left - a.right) * share, b.value) {
+                    simplified.pop();
+                }
+            }
+        }
+        simplified.push((knot, knot_moved));
+    }
+    simplified.into_iter().map(|(knot, _)| knot).collect()
+}
+
+impl PiecewiseLinear {
+    /// Breakpoints have to be in time order, with at most three at any time.
+    pub fn new(points: Vec<Breakpoint>) -> Result<Self, String> {
+        if points.iter().any(|point| !point.value.is_finite()) {
+            return Err("breakpoint values must be finite".to_string());
+        }
+        if points.windows(2).any(|w| w[0].time > w[1].time) {
+            return Err("breakpoints must be in time order".to_string());
+        }
+        if points.windows(4).any(|w| w[0].time == w[3].time) {
+            return Err("at most three breakpoints may share a time".to_string());
+        }
+        Ok(PiecewiseLinear { points })
+    }
+
+    pub fn constant(start: DateTime<Utc>, end: DateTime<Utc>, value: f64) -> Self {
+        Self::line(start, end, value, 0.0)
+    }
+
+    /// Line starting at `value` that changes by `per_minute` every minute.
+    pub fn line(start: DateTime<Utc>, end: DateTime<Utc>, value: f64, per_minute: f64) -> Self {
+        let end_value = value + per_minute * seconds(end - start) / 60.0;
+        let mut points = vec![Breakpoint { time: start, value }];
+        if end > start {
+            points.push(Breakpoint {
+                time: end,
+                value: end_value,
+            });
+        }
+        PiecewiseLinear { points }
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        Some(self.points.first()?.time)
+    }
+
+    pub fn end(&self) -> Option<DateTime<Utc>> {
+        Some(self.points.last()?.time)
+    }
+
+    /// Value at `time`, None outside of the breakpoints.
+    pub fn at(&self, time: DateTime<Utc>) -> Option<f64> {
+        let next = self.points.partition_point(|point| point.time < time);
+        let right = self.points.get(next)?;
+        if right.time == time {
+            // the value of a jump is its second breakpoint
+            return match self.points.get(next + 1) {
+                Some(point) if point.time == time => Some(point.value),
+                _ => Some(right.value),
+            };
+        }
+        let left = self.points.get(next.checked_sub(1)?)?;
+        let share = seconds(time - left.time) / seconds(right.time - left.time);
+        Some(left.value + (right.value - left.value) * share)
+    }
+
+    fn knots(&self) -> Vec<Knot> {
+        let mut knots = knots_of(&self.points);
+        // nothing is approached from outside of the range
+        if let Some(first) = knots.first_mut() {
+            first.left = first.value;
+        }
+        if let Some(last) = knots.last_mut() {
+            last.right = last.value;
+        }
+        knots
+    }
+
+    // breakpoint times of both functions where both are defined
+    fn shared_times(&self, other: &Self) -> Vec<DateTime<Utc>> {
+        let (start, end) = match (self.start(), self.end(), other.start(), other.end()) {
+            (Some(a), Some(b), Some(c), Some(d)) => (a.max(c), b.min(d)),
+            _ => return vec![],
+        };
+        if start > end {
+            return vec![];
+        }
+        let mut times: Vec<DateTime<Utc>> = [start, end]
+            .into_iter()
+            .chain(self.points.iter().chain(&other.points).map(|p| p.time))
+            .filter(|time| (start..=end).contains(time))
+            .collect();
+        times.sort();
+        times.dedup();
+        times
+    }
+
+    // combines both continuous functions where both are defined, `crossings`
+    // adds the times at which they intersect as breakpoints
+    fn combine(&self, other: &Self, crossings: bool, f: impl Fn(f64, f64) -> f64) -> Self {
+        let mut points: Vec<Breakpoint> = vec![];
+        let mut previous: Option<(DateTime<Utc>, f64, f64)> = None;
+        for time in self.shared_times(other) {
+            let (a, b) = (self.at(time).unwrap(), other.at(time).unwrap());
+            if let Some((t0, a0, b0)) = previous {
+                let (d0, d1) = (a0 - b0, a - b);
+                if crossings && d0 * d1 < 0.0 {
+                    let share = d0 / (d0 - d1);
+                    let crossing = a0 + (a - a0) * share;
+                    points.push(Breakpoint {
+                        time: after(t0, seconds(time - t0) * share),
+                        value: crossing,
+                    });
+                }
+            }
+            points.push(Breakpoint {
+                time,
+                value: f(a, b),
+            });
+            previous = Some((time, a, b));
+        }
+        points.dedup_by(|b, a| a.time == b.time);
+        PiecewiseLinear { points }.simplified()
+    }
+
+    fn simplified(self) -> Self {
+        let mut points = Vec::with_capacity(self.points.len());
+        for knot in simplify(self.knots(), |_| true) {
+            push_knot(&mut points, knot);
+        }
+        PiecewiseLinear { points }
+    }
+
+    /// Sum of both functions from the start of the first to the end of the
+    /// last, each counting as zero outside of its breakpoints.
+    pub fn add(&self, other: &Self) -> Self {
+        let (long, short) = match self.points.len() >= other.points.len() {
+            true => (self, other),
+            false => (other, self),
+        };
+        let (Some(start), Some(end)) = (short.start(), short.end()) else {
+            return long.clone();
+        };
+        // the longer function only changes within the range of the shorter
+        // one, which is summed together with the breakpoints next to it
+        let points = &long.points;
+        let mut lo = points.partition_point(|p| p.time < start);
+        let mut hi = points.partition_point(|p| p.time <= end);
+        if lo > 0 {
+            lo = points.partition_point(|p| p.time < points[lo - 1].time);
+        }
+        if hi < points.len() {
+            hi = points.partition_point(|p| p.time <= points[hi].time);
+        }
+        let (a, b) = (knots_of(&points[lo..hi]), short.knots());
+        let mut knots = Vec::with_capacity(a.len() + b.len());
+        // where the shorter function has a breakpoint, elsewhere the longer
+        // one only moves by a line and keeps its shape
+        let mut moved = Vec::with_capacity(a.len() + b.len());
+        let (mut i, mut j) = (0, 0);
+        while let Some(time) = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) => Some(x.time.min(y.time)),
+            (x, y) => x.or(y).map(|knot| knot.time),
+        } {
+            moved.push(b.get(j).is_some_and(|knot| knot.time == time));
+            let (x, y) = (knot_at(&a, &mut i, time), knot_at(&b, &mut j, time));
+            knots.push(Knot {
+                time,
+                left: x.left + y.left,
+                value: x.value + y.value,
+                right: x.right + y.right,
+            });
+        }
+        // the neighbouring breakpoints stay as they are, and nothing is
+        // approached from outside of the range of the sum
+        if let Some(first) = knots.first_mut() {
+            first.left = if lo > 0 { a[0].left } else { first.value };
+        }
+        if let Some(last) = knots.last_mut() {
+            last.right = match hi < points.len() {
+                true => a[a.len() - 1].right,
+                false => last.value,
+            };
+        }
+        let mut sum = Vec::with_capacity(points.len() + 3 * b.len());
+        sum.extend_from_slice(&points[..lo]);
+        for knot in simplify(knots, |i| moved[i]) {
+            push_knot(&mut sum, knot);
+        }
+        sum.extend_from_slice(&points[hi..]);
+        PiecewiseLinear { points: sum }
+    }
+
+    /// The function multiplied by `factor`.
+    pub fn scaled(&self, factor: f64) -> Self {
+        let points = self
+            .points
+            .iter()
+            .map(|point| Breakpoint {
+                time: point.time,
+                value: point.value * factor,
+            })
+            .collect();
+        PiecewiseLinear { points }
+    }
+
+    /// Minimum where both continuous functions are defined.
+    pub fn min(&self, other: &Self) -> Self {
+        self.combine(other, true, f64::min)
+    }
+
+    /// Maximum where both continuous functions are defined.
+    pub fn max(&self, other: &Self) -> Self {
+        self.combine(other, true, f64::max)
+    }
+
+    /// Keeps the function between `lower` and `upper`.
+    pub fn clip(&self, lower: &Self, upper: &Self) -> Self {
+        self.max(lower).min(upper)
+    }
+
+    /// Values at the times of the `freq_minutes` grid, rounded to whole units.
+    pub fn sample(&self, freq_minutes: u32) -> Vec<(DateTime<Utc>, i64)> {
+        let (Some(start), Some(end)) = (self.start(), self.end()) else {
+            return vec![];
+        };
+        TimeRange::minutes(start, end, freq_minutes)
+            .aligned()
+            .inclusive()
+            .map(|time| (time, self.at(time).unwrap().round() as i64))
+            .collect()
+    }
+}
+
+/// Flexibility of a single vehicle as breakpoints rather than rows.
+///
+/// `max_soe` is the asap line up to the target plateau, which uncontrolled
+/// charging follows; `min_soe` charges the critical energy asap and the rest
+/// along the alap line.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct VehicleEnvelope {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<Breakpoint>))]
+    pub min_soe: PiecewiseLinear, // minimum state of charge in Wh
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<Breakpoint>))]
+    pub max_soe: PiecewiseLinear, // maximum and baseline state of charge in Wh
+    pub max_charging_power: i32, // maximum charging power in W
+}
+
+impl VehicleEnvelope {
+    /// Envelope of the demand, None if its target cannot be reached in time.
+    pub fn from_demand(demand: &EnergyDemand) -> Option<Self> {
+        let (target_soc, min_soc) = match &demand.degradation_policy {
+            Some(policy) => policy.limits(demand),
+            None => (demand.target_soc, demand.min_soc),
+        };
+        let one_percent_energy = demand.capacity / 100;
+        let one_minute_energy_state_change = (demand.max_charging_power / 60) as i64;
+        let current_soe = demand.current_soc * one_percent_energy;
+        let target_soe = std::cmp::max(target_soc, demand.current_soc) * one_percent_energy;
+        let critical_soe = min_soc.clamp(demand.current_soc, target_soc.max(demand.current_soc))
+            * one_percent_energy;
+        let available_minutes = (demand.end - demand.start).num_minutes();
+
+        if one_minute_energy_state_change * available_minutes < (target_soe - current_soe) as i64 {
+            return None;
+        }
+
+        let (start, end) = (demand.start, demand.end);
+        let per_minute = one_minute_energy_state_change as f64;
+        let constant = |value: i32| PiecewiseLinear::constant(start, end, value as f64);
+        // asap line: charge at full power until the target is reached
+        let asap = PiecewiseLinear::line(start, end, current_soe as f64, per_minute)
+            .min(&constant(target_soe));
+        // alap line: start as late as possible to still reach the target
+        let alap_start = target_soe as f64 - per_minute * seconds(end - start) / 60.0;
+        let alap = PiecewiseLinear::line(start, end, alap_start, per_minute).max(&constant(0));
+        // critical charge is done asap, the rest alap
+        let min_soe = asap.min(&constant(critical_soe)).max(&alap);
+        Some(VehicleEnvelope {
+            start,
+            end,
+            min_soe,
+            max_soe: asap,
+            max_charging_power: demand.max_charging_power,
+        })
+    }
+
+    /// Charging power under uncontrolled charging, the full power until
+    /// `max_soe` reaches the target and none from then on.
+    pub fn baseline_power(&self) -> PiecewiseLinear {
+        let power = self.max_charging_power as f64;
+        let target_soe = self.max_soe.points().last().map_or(0.0, |p| p.value);
+        let reached = self
+            .max_soe
+            .points()
+            .iter()
+            .find(|point| close(point.value, target_soe))
+            .map_or(self.end, |point| point.time);
+        if reached <= self.start {
+            return PiecewiseLinear::constant(self.start, self.end, 0.0);
+        }
+        let breakpoint = |time, value| Breakpoint { time, value };
+        let mut points = vec![
+            breakpoint(self.start, power),
+            breakpoint(reached, power),
+            breakpoint(reached, 0.0),
+        ];
+        if reached < self.end {
+            points.push(breakpoint(self.end, 0.0));
+        }
+        PiecewiseLinear { points }
+    }
+
+    /// Rows of the envelope at the times of the `freq_minutes` grid.
+    pub fn sample(&self, freq_minutes: u32) -> Vec<AggregationDT> {
+        let baseline_power = self.baseline_power();
+        let max_soe = self.max_soe.sample(freq_minutes);
+        let min_soe = self.min_soe.sample(freq_minutes);
+        max_soe
+            .into_iter()
+            .zip(min_soe)
+            .map(|((time, max_soe), (_, min_soe))| AggregationDT {
+                min_soe,
+                max_soe,
+                max_charging_power: self.max_charging_power as i64,
+                baseline_soe: max_soe,
+                baseline_power: baseline_power.at(time).unwrap_or(0.0).round() as i64,
+                time,
+                local_time: None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::{Breakpoint, PiecewiseLinear};
+    use chrono::{DateTime, TimeZone, Utc};
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_piecewise_operations() {
+        let rising = PiecewiseLinear::line(at(18, 0), at(20, 0), 0.0, 100.0);
+        let plateau = PiecewiseLinear::constant(at(19, 0), at(21, 0), 3000.0);
+
+        // the plateau stays below the line where both are defined
+        let min = rising.min(&plateau);
+        assert_eq!(min, PiecewiseLinear::constant(at(19, 0), at(20, 0), 3000.0));
+
+        let lower = PiecewiseLinear::constant(at(18, 0), at(20, 0), 3000.0);
+        let upper = PiecewiseLinear::constant(at(18, 0), at(20, 0), 9000.0);
+        let clipped = rising.clip(&lower, &upper);
+        let breakpoints: Vec<(DateTime<Utc>, f64)> = clipped
+            .points()
+            .iter()
+            .map(|point| (point.time, point.value))
+            .collect();
+        assert_eq!(
+            breakpoints,
+            [
+                (at(18, 0), 3000.0),
+                (at(18, 30), 3000.0),
+                (at(19, 30), 9000.0),
+                (at(20, 0), 9000.0)
+            ]
+        );
+
+        // the sum counts each function as zero outside of its breakpoints
+        let sum = rising.add(&plateau);
+        assert_eq!(sum.start(), Some(at(18, 0)));
+        assert_eq!(sum.end(), Some(at(21, 0)));
+        assert_eq!(sum.at(at(18, 30)), Some(3000.0));
+        assert_eq!(sum.at(at(19, 30)), Some(12000.0));
+        let samples = sum.sample(15);
+        assert_eq!(samples.len(), 13);
+        assert_eq!(samples[4], (at(19, 0), 9000));
+        assert_eq!(samples[8], (at(20, 0), 15000));
+        assert_eq!(samples[9], (at(20, 15), 3000));
+        assert_eq!(rising.at(at(21, 0)), None);
+
+        // removing the line again leaves the plateau, zero before it
+        let difference = sum.add(&rising.scaled(-1.0));
+        assert_eq!(difference.at(at(18, 30)), Some(0.0));
+        assert_eq!(difference.at(at(19, 0)), Some(3000.0));
+        assert_eq!(difference.at(at(20, 30)), Some(3000.0));
+    }
+
+    #[test]
+    fn test_piecewise_jumps() {
+        let first = PiecewiseLinear::constant(at(18, 0), at(19, 0), 1.0);
+        let second = PiecewiseLinear::constant(at(19, 0), at(20, 0), 2.0);
+        // both count at the time one ends and the other starts
+        let sum = first.add(&second);
+        assert_eq!(sum.at(at(18, 59)), Some(1.0));
+        assert_eq!(sum.at(at(19, 0)), Some(3.0));
+        assert_eq!(sum.at(at(19, 1)), Some(2.0));
+        assert_eq!(sum.points().len(), 5);
+        // apart, both are zero in between
+        let later = PiecewiseLinear::constant(at(19, 30), at(20, 0), 2.0);
+        assert_eq!(first.add(&later).at(at(19, 15)), Some(0.0));
+    }
+
+    #[test]
+    fn test_piecewise_breakpoints_checked() {
+        let point = |hour, value| Breakpoint {
+            time: at(hour, 0),
+            value,
+        };
+        assert!(PiecewiseLinear::new(vec![point(19, 1.0), point(18, 2.0)]).is_err());
+        assert!(PiecewiseLinear::new(vec![point(18, 1.0); 4]).is_err());
+        let jump = PiecewiseLinear::new(vec![point(18, 1.0), point(19, 1.0), point(19, 0.0)]);
+        assert_eq!(jump.unwrap().at(at(19, 0)), Some(0.0));
+
+        let json = serde_json::to_string(&[point(19, 1.0), point(18, 2.0)]).unwrap();
+        assert!(serde_json::from_str::<PiecewiseLinear>(&json).is_err());
+        let json = serde_json::to_string(&[point(18, 1.0), point(19, 2.0)]).unwrap();
+        let parsed: PiecewiseLinear = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.at(at(19, 0)), Some(2.0));
+    }
+}
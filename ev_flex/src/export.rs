@@ -0,0 +1,261 @@
+use crate::aggregation::Aggregation;
+use chrono::{DateTime, FixedOffset, Utc};
+#[cfg(feature = "parquet")]
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, Int64Type};
+#[cfg(feature = "parquet")]
+use parquet::errors::ParquetError;
+#[cfg(feature = "parquet")]
+use parquet::file::properties::WriterProperties;
+#[cfg(feature = "parquet")]
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+#[cfg(feature = "parquet")]
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+use std::io;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Picks the format from an `Accept` header, falling back to JSON.
+    pub fn from_accept(accept: &str) -> Self {
+        for media_type in accept.split(',') {
+            match media_type.split(';').next().unwrap_or("").trim() {
+                "text/csv" => return ExportFormat::Csv,
+                #[cfg(feature = "parquet")]
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    return ExportFormat::Parquet
+                }
+                "application/json" => return ExportFormat::Json,
+                _ => {}
+            }
+        }
+        ExportFormat::Json
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv",
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// One flat row of an exported envelope, identical for CSV and Parquet.
+///
+/// Flexibility columns are empty for the last row, which does not start an interval.
+#[derive(Serialize)]
+pub struct ExportRow {
+    pub group: Option<String>,
+    pub time: DateTime<Utc>,
+    pub local_time: Option<DateTime<FixedOffset>>,
+    pub min_soe: i64,
+    pub max_soe: i64,
+    pub max_charging_power: i64,
+    pub baseline_soe: i64,
+    pub baseline_power: i64,
+    pub min_power: Option<i64>,
+    pub max_power: Option<i64>,
+    pub upward_flex: Option<i64>,
+    pub downward_flex: Option<i64>,
+    pub upward_duration: Option<i64>,
+    pub downward_duration: Option<i64>,
+}
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str = "
+message aggregation {
+    OPTIONAL BYTE_ARRAY group (UTF8);
+    REQUIRED INT64 time (TIMESTAMP(MILLIS,true));
+    OPTIONAL BYTE_ARRAY local_time (UTF8);
+    REQUIRED INT64 min_soe;
+    REQUIRED INT64 max_soe;
+    REQUIRED INT64 max_charging_power;
+    REQUIRED INT64 baseline_soe;
+    REQUIRED INT64 baseline_power;
+    OPTIONAL INT64 min_power;
+    OPTIONAL INT64 max_power;
+    OPTIONAL INT64 upward_flex;
+    OPTIONAL INT64 downward_flex;
+    OPTIONAL INT64 upward_duration;
+    OPTIONAL INT64 downward_duration;
+}
+";
+
+pub fn to_rows(group: Option<&str>, aggregation: &Aggregation) -> Vec<ExportRow> {
+    let mut flexibility = aggregation.flexibility.iter().peekable();
+    aggregation
+        .series
+        .iter()
+        .map(|dt| {
+            let flex = flexibility.next_if(|flex| flex.time == dt.time);
+            ExportRow {
+                group: group.map(str::to_string),
+                time: dt.time,
+                local_time: dt.local_time,
+                min_soe: dt.min_soe,
+                max_soe: dt.max_soe,
+                max_charging_power: dt.max_charging_power,
+                baseline_soe: dt.baseline_soe,
+                baseline_power: dt.baseline_power,
+                min_power: flex.map(|flex| flex.min_power),
+                max_power: flex.map(|flex| flex.max_power),
+                upward_flex: flex.map(|flex| flex.upward_flex),
+                downward_flex: flex.map(|flex| flex.downward_flex),
+                upward_duration: flex.map(|flex| flex.upward_duration),
+                downward_duration: flex.map(|flex| flex.downward_duration),
+            }
+        })
+        .collect()
+}
+
+/// CSV with a header taken from the field names of the rows.
+pub fn to_csv<T: Serialize>(rows: &[T]) -> io::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().map_err(|err| err.into_error())
+}
+
+#[cfg(feature = "parquet")]
+fn write_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<'_, &mut Vec<u8>>,
+    values: Vec<Option<T::T>>,
+) -> Result<(), ParquetError> {
+    let mut column = row_group
+        .next_column()?
+        .ok_or_else(|| ParquetError::General("column missing from schema".to_string()))?;
+    let definition_levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+    let values: Vec<T::T> = values.into_iter().flatten().collect();
+    column
+        .typed::<T>()
+        .write_batch(&values, Some(&definition_levels), None)?;
+    column.close()
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet(rows: &[ExportRow], buffer: &mut Vec<u8>) -> Result<(), ParquetError> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(buffer, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let int64 = |column: fn(&ExportRow) -> Option<i64>| rows.iter().map(column).collect();
+    write_column::<ByteArrayType>(
+        &mut row_group,
+        rows.iter()
+            .map(|row| row.group.as_deref().map(ByteArray::from))
+            .collect(),
+    )?;
+    write_column::<Int64Type>(
+        &mut row_group,
+        int64(|row| Some(row.time.timestamp_millis())),
+    )?;
+    write_column::<ByteArrayType>(
+        &mut row_group,
+        rows.iter()
+            .map(|row| {
+                row.local_time
+                    .map(|time| ByteArray::from(time.to_rfc3339().as_str()))
+            })
+            .collect(),
+    )?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| Some(row.min_soe)))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| Some(row.max_soe)))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| Some(row.max_charging_power)))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| Some(row.baseline_soe)))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| Some(row.baseline_power)))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.min_power))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.max_power))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.upward_flex))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.downward_flex))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.upward_duration))?;
+    write_column::<Int64Type>(&mut row_group, int64(|row| row.downward_duration))?;
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+pub fn to_parquet(rows: &[ExportRow]) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![];
+    write_parquet(rows, &mut buffer).map_err(io::Error::other)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::{Aggregation, AggregationDT};
+    #[cfg(feature = "parquet")]
+    use crate::export::to_parquet;
+    use crate::export::{to_csv, to_rows, ExportFormat};
+    use crate::flexibility::power_flex_series;
+    #[cfg(feature = "parquet")]
+    use bytes::Bytes;
+    use chrono::{Duration, TimeZone, Utc};
+    #[cfg(feature = "parquet")]
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn aggregation() -> Aggregation {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 18, 0, 0).unwrap();
+        let series: Vec<AggregationDT> = (0..3)
+            .map(|i| AggregationDT {
+                min_soe: 0,
+                max_soe: 1000 * i,
+                max_charging_power: 4000,
+                baseline_soe: 1000 * i,
+                baseline_power: 4000,
+                time: start + Duration::minutes(15 * i),
+                local_time: None,
+            })
+            .collect();
+        Aggregation {
+            start,
+            end: series[2].time,
+            flexibility: power_flex_series(&series),
+            series,
+        }
+    }
+
+    #[test]
+    fn test_export_format_from_accept() {
+        assert_eq!(ExportFormat::from_accept("text/csv"), ExportFormat::Csv);
+        #[cfg(feature = "parquet")]
+        assert_eq!(
+            ExportFormat::from_accept("application/vnd.apache.parquet;q=0.9, */*"),
+            ExportFormat::Parquet
+        );
+        assert_eq!(ExportFormat::from_accept("*/*"), ExportFormat::Json);
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let csv = String::from_utf8(to_csv(&to_rows(Some("a"), &aggregation())).unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("group,time,local_time,min_soe,max_soe"));
+        assert!(lines[1].starts_with("a,2023-05-01T18:00:00Z,,0,0,4000"));
+        assert!(lines[3].ends_with(",,,,,,"));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_to_parquet() {
+        let parquet = to_parquet(&to_rows(None, &aggregation())).unwrap();
+        let reader = SerializedFileReader::new(Bytes::from(parquet)).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 3);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 14);
+    }
+}
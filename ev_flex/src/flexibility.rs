@@ -0,0 +1,113 @@
+use crate::aggregation::AggregationDT;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PowerFlexDT {
+    pub min_power: i64,     // minimum power keeping state of charge above min_soe in W
+    pub max_power: i64,     // maximum power keeping state of charge below max_soe in W
+    pub upward_flex: i64,   // max_power above baseline power in W
+    pub downward_flex: i64, // baseline power above min_power in W
+    pub upward_duration: i64, // minutes every vehicle can sustain its max_power from the baseline
+    pub downward_duration: i64, // minutes every vehicle can sustain its min_power from the baseline
+    pub time: DateTime<Utc>, // start of the interval
+}
+
+/// Longest duration that is reported, durations are not looked for beyond it.
+pub const MAX_FLEX_DURATION_MINUTES: i64 = 24 * 60;
+
+fn minutes(from: &AggregationDT, to: &AggregationDT) -> i64 {
+    (to.time - from.time).num_minutes()
+}
+
+// Average power needed to move from `soe` to `target_soe` within `minutes`.
+fn power_to_reach(soe: i64, target_soe: i64, minutes: i64) -> i64 {
+    (target_soe - soe) * 60 / minutes
+}
+
+// Minutes a constant `power` can be held from `start`, starting at its baseline state of charge,
+// up to `MAX_FLEX_DURATION_MINUTES`.
+fn sustainable_minutes(series: &[AggregationDT], start: usize, power: i64) -> i64 {
+    let mut soe = series[start].baseline_soe;
+    let mut sustained = 0;
+    for window in series[start..].windows(2) {
+        if sustained >= MAX_FLEX_DURATION_MINUTES {
+            break;
+        }
+        let interval = minutes(&window[0], &window[1]);
+        soe += power * interval / 60;
+        let within_bounds = soe >= window[1].min_soe && soe <= window[1].max_soe;
+        if power > window[0].max_charging_power || !within_bounds {
+            break;
+        }
+        sustained += interval;
+    }
+    sustained.min(MAX_FLEX_DURATION_MINUTES)
+}
+
+/// Feasible power range around the baseline for every interval of the series
+/// of a single vehicle.
+///
+/// Each interval spans from one row of the series to the next, so the last row
+/// does not start an interval. A fleet sums these per vehicle, as the summed
+/// series would count arriving and departing vehicles as charged energy.
+pub fn power_flex_series(series: &[AggregationDT]) -> Vec<PowerFlexDT> {
+    series
+        .windows(2)
+        .enumerate()
+        .filter(|(_, window)| minutes(&window[0], &window[1]) > 0)
+        .map(|(i, window)| {
+            let (current, next) = (&window[0], &window[1]);
+            let interval = minutes(current, next);
+            let soe = current.baseline_soe;
+            let limit = current.max_charging_power;
+            let min_power = power_to_reach(soe, next.min_soe, interval).clamp(0, limit);
+            let max_power = power_to_reach(soe, next.max_soe, interval).clamp(0, limit);
+            PowerFlexDT {
+                min_power,
+                max_power,
+                upward_flex: std::cmp::max(max_power - current.baseline_power, 0),
+                downward_flex: std::cmp::max(current.baseline_power - min_power, 0),
+                upward_duration: sustainable_minutes(series, i, max_power),
+                downward_duration: sustainable_minutes(series, i, min_power),
+                time: current.time,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::AggregationDT;
+    use crate::flexibility::power_flex_series;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_power_flex_series() {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 18, 0, 0).unwrap();
+        let row = |minutes: i64, min_soe: i64, max_soe: i64, baseline_power: i64| AggregationDT {
+            min_soe,
+            max_soe,
+            max_charging_power: 4000,
+            baseline_soe: max_soe,
+            baseline_power,
+            time: start + Duration::minutes(minutes),
+            local_time: None,
+        };
+        let series = vec![
+            row(0, 0, 0, 4000),
+            row(15, 0, 1000, 0),
+            row(30, 0, 1000, 0),
+            row(45, 1000, 1000, 0),
+        ];
+        let flex = power_flex_series(&series);
+        assert_eq!(flex.len(), 3);
+        assert_eq!(flex[0].max_power, 4000);
+        assert_eq!(flex[0].min_power, 0);
+        assert_eq!(flex[0].downward_flex, 4000);
+        assert_eq!(flex[0].downward_duration, 30);
+        assert_eq!(flex[1].upward_flex, 0);
+        assert_eq!(flex[1].downward_duration, 30);
+    }
+}
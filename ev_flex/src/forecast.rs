@@ -0,0 +1,171 @@
+use crate::aggregation::{Aggregation, FleetEnvelope};
+use crate::demand::{Demands, EnergyDemand};
+use crate::envelope::VehicleEnvelope;
+use crate::market::MarketPeriods;
+use crate::schedule::{session_vehicle, to_utc};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Expected envelope of a delivery day, split into the demands that are
+/// already known and those forecast for vehicles that have not sent one yet.
+#[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Forecast {
+    pub delivery_day: NaiveDate,
+    pub history_days: usize, // past days the forecast sessions are learned from
+    pub expected_sessions: f64, // sessions expected from vehicles without a known demand
+    pub known: Aggregation,  // envelope of the known demands
+    pub forecast: Aggregation, // expected envelope of the vehicles not known yet
+    pub total: Aggregation,  // known and forecast envelope together
+}
+
+// the session moved to arrive at the same local time on `day`
+fn shifted(demand: &EnergyDemand, periods: &MarketPeriods, day: NaiveDate) -> Option<EnergyDemand> {
+    let arrival = demand.start.with_timezone(&periods.timezone).time();
+    let start = to_utc(periods.timezone, day.and_time(arrival));
+    Some(EnergyDemand {
+        start,
+        end: start + (demand.end - demand.start),
+        ..demand.clone()
+    })
+}
+
+/// Forecasts the delivery day of `periods` from the sessions that ended
+/// before `now`.
+///
+/// Past sessions that arrived on the same weekday are moved to the delivery
+/// day and averaged over the days they were observed on, so the forecast is
+/// the envelope of a typical such day. Without any session on that weekday,
+/// every past day counts alike. Vehicles with a known demand on the delivery
+/// day are left out of the forecast.
+pub fn forecast(
+    db: &Demands,
+    periods: &MarketPeriods,
+    now: DateTime<Utc>,
+) -> Result<Forecast, String> {
+    let (Some(day), Some((day_start, day_end))) = (periods.delivery_day, periods.delivery_bounds())
+    else {
+        return Err("A forecast needs a delivery_day!".to_string());
+    };
+    let stored = db.all();
+    let known: Vec<EnergyDemand> = stored
+        .iter()
+        .filter(|demand| demand.start < day_end && demand.end > day_start)
+        .map(|demand| db.with_policy(demand).into_owned())
+        .collect();
+    let known_vehicles: HashSet<&str> = known
+        .iter()
+        .map(|demand| session_vehicle(&demand.vehicle_id))
+        .collect();
+
+    let history: Vec<EnergyDemand> = db
+        .history()
+        .into_iter()
+        .chain(stored.iter().cloned())
+        .filter(|demand| demand.end <= now)
+        // sessions expanded from a schedule were planned, not observed
+        .filter(|demand| session_vehicle(&demand.vehicle_id) == demand.vehicle_id)
+        .collect();
+    let arrival_day =
+        |demand: &EnergyDemand| demand.start.with_timezone(&periods.timezone).date_naive();
+    let same_weekday: Vec<&EnergyDemand> = history
+        .iter()
+        .filter(|demand| arrival_day(demand).weekday() == day.weekday())
+        .collect();
+    let samples = match same_weekday.is_empty() {
+        true => history.iter().collect(),
+        false => same_weekday,
+    };
+    let history_days = samples
+        .iter()
+        .map(|demand| arrival_day(demand))
+        .collect::<HashSet<NaiveDate>>()
+        .len();
+
+    let expected: Vec<EnergyDemand> = samples
+        .into_iter()
+        .filter(|demand| !known_vehicles.contains(session_vehicle(&demand.vehicle_id)))
+        .filter_map(|demand| shifted(demand, periods, day))
+        .map(|demand| db.with_policy(&demand).into_owned())
+        .collect();
+    let factor = 1.0 / history_days.max(1) as f64;
+    let mut forecast = FleetEnvelope::default();
+    for vehicle in expected.iter().filter_map(VehicleEnvelope::from_demand) {
+        forecast.add_weighted(&vehicle, factor);
+    }
+    let mut total = FleetEnvelope::default();
+    for vehicle in known.iter().filter_map(VehicleEnvelope::from_demand) {
+        total.add(&vehicle);
+    }
+    let known = total.to_aggregation();
+    let forecast_aggregation = forecast.to_aggregation();
+    let total = total.merge(forecast);
+
+    Ok(Forecast {
+        delivery_day: day,
+        history_days,
+        expected_sessions: expected.len() as f64 * factor,
+        known: periods.align(known),
+        forecast: periods.align(forecast_aggregation),
+        total: periods.align(total.to_aggregation()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::{Demands, EnergyDemand};
+    use crate::forecast::forecast;
+    use crate::market::MarketPeriods;
+    use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
+    fn session(vehicle_id: &str, start: DateTime<Utc>) -> EnergyDemand {
+        EnergyDemand::example_session(vehicle_id, start, start + Duration::hours(12))
+    }
+
+    #[test]
+    fn test_weekday_forecast() {
+        let db = Demands::new();
+        let monday = |day| Utc.with_ymd_and_hms(2023, 5, day, 18, 0, 0).unwrap();
+        // "a" came on two Mondays, "b" on one of them, "c" on a Tuesday
+        db.insert(session("a", monday(1)));
+        db.insert(session("a", monday(8)));
+        db.insert(session("b", monday(8)));
+        db.insert(session("c", monday(9)));
+        // planned sessions of recurring demands are not learned from
+        db.insert(session("bus@2023-05-08", monday(8)));
+        assert_eq!(db.history().len(), 1);
+
+        let now = Utc.with_ymd_and_hms(2023, 5, 14, 0, 0, 0).unwrap();
+        let day = NaiveDate::from_ymd_opt(2023, 5, 15);
+        let periods = MarketPeriods::new(None, 60, day).unwrap();
+        let result = forecast(&db, &periods, now).unwrap();
+        assert_eq!(result.history_days, 2);
+        assert_eq!(result.expected_sessions, 1.5);
+        assert!(result.known.series.is_empty());
+        // one and a half vehicles charging at full power at 19:00
+        let at = |hour| {
+            let time = Utc.with_ymd_and_hms(2023, 5, 15, hour, 0, 0).unwrap();
+            result
+                .total
+                .series
+                .iter()
+                .find(|dt| dt.time == time)
+                .unwrap()
+        };
+        assert_eq!(at(19).max_charging_power, 16500);
+
+        // once "b" is known for the day, only "a" is forecast
+        db.insert(session("b", monday(15)));
+        let result = forecast(&db, &periods, now).unwrap();
+        assert_eq!(result.expected_sessions, 1.0);
+        assert_eq!(result.known.series[0].max_charging_power, 11000);
+        let total = result
+            .total
+            .series
+            .iter()
+            .find(|dt| dt.time == monday(15))
+            .unwrap();
+        assert_eq!(total.max_charging_power, 22000);
+    }
+}
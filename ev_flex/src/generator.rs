@@ -0,0 +1,147 @@
+use crate::demand::EnergyDemand;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::Normal;
+
+/// Shape of a synthetic fleet. Times are hours relative to midnight UTC of `day`.
+pub struct FleetConfig {
+    pub vehicles: usize,
+    pub seed: u64,
+    pub day: NaiveDate,
+    pub arrival_mean_hours: f64,
+    pub arrival_std_hours: f64,
+    pub dwell_mean_hours: f64,
+    pub dwell_std_hours: f64,
+    pub min_dwell_hours: f64,
+    pub capacities: Vec<(i32, f64)>, // battery capacity in Wh with relative weight
+    pub charging_powers: Vec<(i32, f64)>, // charging power in W with relative weight
+    pub current_soc_mean: f64,
+    pub current_soc_std: f64,
+    pub min_soc: i32,
+    pub target_soc: i32,
+    pub fleets: usize,
+    pub grid_nodes: usize,
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        Self {
+            vehicles: 100,
+            seed: 0,
+            day: NaiveDate::from_ymd_opt(2023, 5, 1).unwrap(),
+            arrival_mean_hours: 18.0,
+            arrival_std_hours: 2.0,
+            dwell_mean_hours: 12.0,
+            dwell_std_hours: 2.5,
+            min_dwell_hours: 1.0,
+            capacities: vec![(40000, 0.3), (60000, 0.4), (80000, 0.2), (100000, 0.1)],
+            charging_powers: vec![
+                (3700, 0.2),
+                (7400, 0.3),
+                (11000, 0.3),
+                (22000, 0.1),
+                (50000, 0.1),
+            ],
+            current_soc_mean: 35.0,
+            current_soc_std: 15.0,
+            min_soc: 20,
+            target_soc: 80,
+            fleets: 1,
+            grid_nodes: 1,
+        }
+    }
+}
+
+fn weighted(options: &[(i32, f64)]) -> (Vec<i32>, WeightedIndex<f64>) {
+    let values = options.iter().map(|(value, _)| *value).collect();
+    let index = WeightedIndex::new(options.iter().map(|(_, weight)| *weight))
+        .expect("weights must be non-negative and not all zero");
+    (values, index)
+}
+
+fn hours(hours: f64) -> Duration {
+    Duration::seconds((hours * 3600.0) as i64)
+}
+
+/// Generates a reproducible population of demands; the same config yields the same fleet.
+pub fn generate_fleet(config: &FleetConfig) -> Vec<EnergyDemand> {
+    let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+    let midnight: DateTime<Utc> = config.day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let arrival = Normal::new(config.arrival_mean_hours, config.arrival_std_hours).unwrap();
+    let dwell = Normal::new(config.dwell_mean_hours, config.dwell_std_hours).unwrap();
+    let current_soc = Normal::new(config.current_soc_mean, config.current_soc_std).unwrap();
+    let (capacities, capacity_index) = weighted(&config.capacities);
+    let (powers, power_index) = weighted(&config.charging_powers);
+
+    (0..config.vehicles)
+        .map(|i| {
+            let start = midnight + hours(arrival.sample(&mut rng));
+            let end = start + hours(dwell.sample(&mut rng).max(config.min_dwell_hours));
+            let max_charging_power = powers[power_index.sample(&mut rng)];
+            let current_soc =
+                (current_soc.sample(&mut rng).round() as i32).clamp(0, config.target_soc);
+            EnergyDemand {
+                vehicle_id: format!("ev-{:05}", i),
+                min_soc: config.min_soc,
+                max_soc: 100,
+                target_soc: config.target_soc,
+                current_soc,
+                capacity: capacities[capacity_index.sample(&mut rng)],
+                max_charging_power,
+                // round to whole minutes, as demands are usually reported
+                start: start - Duration::seconds(start.timestamp() % 60),
+                end: end - Duration::seconds(end.timestamp() % 60),
+                fleet: Some(format!("fleet-{}", i % config.fleets.max(1))),
+                // AC charging tops out at 22 kW, anything above is a DC charger
+                charger_type: Some(match max_charging_power > 22000 {
+                    true => "dc".to_string(),
+                    false => "ac".to_string(),
+                }),
+                grid_node: Some(format!("node-{}", i % config.grid_nodes.max(1))),
+                customer: None,
+                degradation_policy: None,
+                departure_distribution: None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generator::{generate_fleet, FleetConfig};
+
+    #[test]
+    fn test_generate_fleet_reproducible() {
+        let config = FleetConfig {
+            vehicles: 50,
+            seed: 42,
+            ..Default::default()
+        };
+        let a = serde_json::to_string(&generate_fleet(&config)).unwrap();
+        let b = serde_json::to_string(&generate_fleet(&config)).unwrap();
+        assert_eq!(a, b);
+
+        let fleet = generate_fleet(&config);
+        assert_eq!(fleet.len(), 50);
+        assert!(fleet.iter().all(|demand| demand.start < demand.end));
+        assert!(fleet
+            .iter()
+            .all(|demand| demand.current_soc <= demand.target_soc));
+    }
+
+    #[test]
+    fn test_generate_fleet_charger_types() {
+        let fleet = generate_fleet(&FleetConfig::default());
+        for charger_type in ["ac", "dc"] {
+            assert!(fleet
+                .iter()
+                .any(|demand| demand.charger_type.as_deref() == Some(charger_type)));
+        }
+        assert!(fleet.iter().all(|demand| {
+            (demand.charger_type.as_deref() == Some("dc")) == (demand.max_charging_power > 22000)
+        }));
+    }
+}
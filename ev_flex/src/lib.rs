@@ -0,0 +1,66 @@
+//! Charging flexibility of electric vehicle fleets.
+//!
+//! An [`EnergyDemand`] describes one charging session. [`create_flex_series`]
+//! turns it into the envelope of feasible states of charge, and [`Demands`]
+//! keeps the envelope of a whole fleet up to date as demands come and go.
+//!
+//! The library API is the list of re-exports below. The integrations each have
+//! a module of their own behind a feature: the HTTP API in `api` with the
+//! `server` feature, MQTT ingestion in `mqtt` with the `mqtt` feature, and
+//! webhook notifications in `webhook` with the `webhooks` feature. Parquet
+//! export, OpenAPI schemas, parallel computation and the fleet generator
+//! need the `parquet`, `openapi`, `parallel` and `generator` features.
+
+mod aggregation;
+#[cfg(feature = "server")]
+pub mod api;
+mod config;
+mod demand;
+mod departure;
+mod envelope;
+mod export;
+mod flexibility;
+mod forecast;
+#[cfg(feature = "generator")]
+mod generator;
+mod market;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+mod policy;
+mod replay;
+mod reserve;
+mod schedule;
+mod settlement;
+mod snapshot;
+mod utils;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+
+pub use aggregation::{
+    create_flex_series, fleet_aggregation, flex_series, resample_series, vehicle_aggregation,
+    vehicle_series, Aggregation, AggregationDT, FleetEnvelope, GroupAggregation,
+    AGGREGATION_FREQ_MINUTES,
+};
+pub use config::{Config, MqttConfig, WebhookConfig};
+pub use demand::{
+    DemandEvent, Demands, EnergyDemand, GroupKey, SocReading, DEFAULT_CONFIDENCE_LEVELS,
+    HISTORY_SIZE, READINGS_SIZE,
+};
+pub use departure::DepartureProbability;
+pub use envelope::{Breakpoint, PiecewiseLinear, VehicleEnvelope};
+#[cfg(feature = "parquet")]
+pub use export::to_parquet;
+pub use export::{to_csv, to_rows, ExportFormat, ExportRow};
+pub use flexibility::{power_flex_series, PowerFlexDT, MAX_FLEX_DURATION_MINUTES};
+pub use forecast::{forecast, Forecast};
+#[cfg(feature = "generator")]
+pub use generator::{generate_fleet, FleetConfig};
+pub use market::MarketPeriods;
+pub use policy::DegradationPolicy;
+pub use replay::{read_demands, Replay, ReplayStatistics, ReplayStep};
+pub use reserve::{qualify, ReserveBlock, ReserveProduct, ReserveQualification};
+pub use schedule::{session_id, session_vehicle, RecurringDemand, Schedules};
+pub use settlement::{
+    settle_days, settle_sessions, DaySettlement, SessionSettlement, SettlementLevel,
+};
+pub use snapshot::Snapshot;
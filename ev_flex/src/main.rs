@@ -1,22 +1,124 @@
-use crate::api::{handle_aggregation_request, handle_energy_demand};
-use crate::demand::Demands;
+use actix_web::dev::ServerHandle;
+use actix_web::rt::signal;
+use actix_web::rt::task::spawn_blocking;
 use actix_web::{web::Data, App, HttpServer};
+use chrono::Utc;
+use ev_flex::api::configure;
+use ev_flex::webhook::Webhooks;
+use ev_flex::Config;
+use ev_flex::Demands;
+use ev_flex::Schedules;
+use ev_flex::Snapshot;
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
 
-mod aggregation;
-mod api;
-mod demand;
-mod utils;
+// SIGINT and SIGTERM both drain in-flight requests before the server stops
+async fn stop_on_signal(server: ServerHandle) {
+    let interrupt = Box::pin(signal::ctrl_c());
+    #[cfg(unix)]
+    {
+        let kind = signal::unix::SignalKind::terminate();
+        match signal::unix::signal(kind) {
+            Ok(mut terminate) => {
+                select(interrupt, Box::pin(terminate.recv())).await;
+            }
+            Err(_) => {
+                let _ = interrupt.await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = interrupt.await;
+    eprintln!("Shutting down, finishing requests in flight");
+    server.stop(true).await;
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_data = Data::new(Demands::new());
-    HttpServer::new(move || {
+    let config = Config::from_env().map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let app_data = Data::new(Demands::with_confidence_levels(&config.confidence_levels));
+    let schedules = Data::new(Schedules::new(chrono::Duration::hours(
+        config.schedule_horizon_hours.into(),
+    )));
+    if let Some(path) = &config.snapshot_path {
+        if let Some(snapshot) = Snapshot::read(path)? {
+            eprintln!(
+                "Restoring {} demands from {}",
+                snapshot.demands.len(),
+                path.display()
+            );
+            snapshot.restore(&app_data, &schedules);
+        }
+    }
+    #[cfg(feature = "mqtt")]
+    let ingestion = config
+        .mqtt
+        .clone()
+        .map(|mqtt| actix_web::rt::spawn(ev_flex::mqtt::run(app_data.clone().into_inner(), mqtt)));
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.is_some() {
+        eprintln!("EV_FLEX_MQTT_HOST is ignored, the server is built without the mqtt feature");
+    }
+    let webhooks = Data::from(Webhooks::start(
+        app_data.clone().into_inner(),
+        config.webhooks,
+    ));
+    let (db, refreshed) = (app_data.clone(), schedules.clone());
+    let (stop_refresh, mut refresh_stopped) = oneshot::channel::<()>();
+    let refresh = actix_web::rt::spawn(async move {
+        // keeps the expanded sessions ahead of time and drops those that have left,
+        // off the server threads; a refresh in progress finishes before it stops
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        while let Either::Left(_) = select(Box::pin(interval.tick()), &mut refresh_stopped).await {
+            let (db, refreshed) = (db.clone(), refreshed.clone());
+            let _ = spawn_blocking(move || refreshed.refresh(&db, Utc::now())).await;
+        }
+    });
+    let (db, webhooks_at_exit, schedules_at_exit) =
+        (app_data.clone(), webhooks.clone(), schedules.clone());
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
-            .service(handle_energy_demand)
-            .service(handle_aggregation_request)
+            .app_data(webhooks.clone())
+            .app_data(schedules.clone())
+            .configure(configure)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .shutdown_timeout(config.shutdown_timeout_secs)
+    .disable_signals()
+    .bind((config.host, config.port))?
+    .run();
+    actix_web::rt::spawn(stop_on_signal(server.handle()));
+    server.await?;
+
+    // requests have finished or were cut off by the timeout; stop everything
+    // else that changes the store or reacts to it before it is saved
+    let _ = stop_refresh.send(());
+    let _ = refresh.await;
+    db.remove_listeners();
+    #[cfg(feature = "mqtt")]
+    if let Some(ingestion) = ingestion {
+        let _ = ingestion.await;
+    }
+    let snapshot = Snapshot::of(&db, &schedules_at_exit);
+    let flushed = match &config.snapshot_path {
+        Some(path) => snapshot.write(path).map(|()| {
+            eprintln!(
+                "Flushed {} demands to {}",
+                snapshot.demands.len(),
+                path.display()
+            )
+        }),
+        None => {
+            eprintln!(
+                "EV_FLEX_SNAPSHOT_PATH is not set, {} demands are not kept",
+                snapshot.demands.len()
+            );
+            Ok(())
+        }
+    };
+    // the store is saved, deliveries still being retried may take their time
+    webhooks_at_exit.join();
+    flushed
 }
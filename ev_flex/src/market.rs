@@ -0,0 +1,196 @@
+use crate::aggregation::{Aggregation, AGGREGATION_FREQ_MINUTES};
+use crate::schedule::to_utc;
+use chrono::{DateTime, Days, NaiveDate, Timelike, Utc};
+use chrono_tz::Tz;
+
+/// Delivery periods of a market operating on local time.
+pub struct MarketPeriods {
+    pub timezone: Tz,
+    pub period_minutes: u32,
+    pub delivery_day: Option<NaiveDate>,
+}
+
+impl MarketPeriods {
+    /// Builds market periods from optional request parameters, `None` if none is given.
+    pub fn from_query(
+        timezone: Option<&str>,
+        period_minutes: Option<u32>,
+        delivery_day: Option<NaiveDate>,
+    ) -> Result<Option<Self>, String> {
+        if timezone.is_none() && period_minutes.is_none() && delivery_day.is_none() {
+            return Ok(None);
+        }
+        let period_minutes = period_minutes.unwrap_or(AGGREGATION_FREQ_MINUTES);
+        Self::new(timezone, period_minutes, delivery_day).map(Some)
+    }
+
+    /// Market periods in the given timezone, UTC if none is given.
+    pub fn new(
+        timezone: Option<&str>,
+        period_minutes: u32,
+        delivery_day: Option<NaiveDate>,
+    ) -> Result<Self, String> {
+        let timezone: Tz = match timezone {
+            Some(name) => name
+                .parse()
+                .map_err(|_| format!("Unknown timezone {}!", name))?,
+            None => Tz::UTC,
+        };
+        if period_minutes == 0 || !period_minutes.is_multiple_of(AGGREGATION_FREQ_MINUTES) {
+            return Err(format!(
+                "Period must be a multiple of {} minutes!",
+                AGGREGATION_FREQ_MINUTES
+            ));
+        }
+        Ok(MarketPeriods {
+            timezone,
+            period_minutes,
+            delivery_day,
+        })
+    }
+
+    // midnight may fall into a DST gap in a few zones, the day then starts at
+    // the gap's end
+    fn local_midnight(&self, day: NaiveDate) -> DateTime<Utc> {
+        to_utc(self.timezone, day.and_hms_opt(0, 0, 0).unwrap())
+    }
+
+    /// UTC bounds of the delivery day, 23 or 25 hours long on DST transitions.
+    pub fn delivery_bounds(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let day = self.delivery_day?;
+        let next_day = day.checked_add_days(Days::new(1))?;
+        Some((self.local_midnight(day), self.local_midnight(next_day)))
+    }
+
+    pub fn is_period_start(&self, time: DateTime<Utc>) -> bool {
+        let local = time.with_timezone(&self.timezone);
+        let minute_of_day = local.hour() * 60 + local.minute();
+        local.second() == 0 && minute_of_day.is_multiple_of(self.period_minutes)
+    }
+
+    /// Samples the aggregation at the start of every local delivery period,
+    /// labelling each row with its local time.
+    ///
+    /// The flexibility of a period is that of the interval starting it, its
+    /// durations tell whether it lasts for the whole period.
+    pub fn align(&self, mut aggregation: Aggregation) -> Aggregation {
+        let bounds = self.delivery_bounds();
+        aggregation.series.retain(|dt| {
+            let within_day = match bounds {
+                Some((start, end)) => dt.time >= start && dt.time <= end,
+                None => true,
+            };
+            within_day && self.is_period_start(dt.time)
+        });
+        aggregation.flexibility.retain(|flex| {
+            let within_day = match bounds {
+                Some((start, end)) => flex.time >= start && flex.time < end,
+                None => true,
+            };
+            within_day && self.is_period_start(flex.time)
+        });
+        for dt in aggregation.series.iter_mut() {
+            dt.local_time = Some(dt.time.with_timezone(&self.timezone).fixed_offset());
+        }
+        if let Some((start, end)) = bounds {
+            aggregation.start = start;
+            aggregation.end = end;
+        }
+        aggregation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::{Aggregation, AggregationDT};
+    use crate::flexibility::power_flex_series;
+    use crate::market::MarketPeriods;
+    use chrono::{Duration, NaiveDate, TimeZone, Utc};
+
+    fn aggregation(from: &str, hours: i64) -> Aggregation {
+        let start = from.parse().unwrap();
+        let series: Vec<AggregationDT> = (0..=hours * 4)
+            .map(|i| AggregationDT {
+                min_soe: 0,
+                max_soe: 0,
+                max_charging_power: 0,
+                baseline_soe: 0,
+                baseline_power: 0,
+                time: start + Duration::minutes(15 * i),
+                local_time: None,
+            })
+            .collect();
+        Aggregation {
+            start,
+            end: start + Duration::hours(hours),
+            flexibility: power_flex_series(&series),
+            series,
+        }
+    }
+
+    #[test]
+    fn test_dst_delivery_days() {
+        let day = |d| NaiveDate::from_ymd_opt(2023, 3, d);
+        let periods = MarketPeriods::from_query(Some("Europe/Berlin"), Some(60), day(26))
+            .unwrap()
+            .unwrap();
+        let aligned = periods.align(aggregation("2023-03-25T20:00:00Z", 30));
+        // 23 hourly periods plus the closing boundary
+        assert_eq!(aligned.series.len(), 24);
+        assert_eq!(
+            aligned.start,
+            Utc.with_ymd_and_hms(2023, 3, 25, 23, 0, 0).unwrap()
+        );
+        assert_eq!(
+            aligned.series[2].local_time.unwrap().to_rfc3339(),
+            "2023-03-26T03:00:00+02:00"
+        );
+
+        let periods = MarketPeriods::from_query(
+            Some("Europe/Berlin"),
+            Some(60),
+            NaiveDate::from_ymd_opt(2023, 10, 29),
+        )
+        .unwrap()
+        .unwrap();
+        let aligned = periods.align(aggregation("2023-10-28T20:00:00Z", 30));
+        assert_eq!(aligned.series.len(), 26);
+        assert_eq!(aligned.flexibility.len(), 25);
+    }
+
+    #[test]
+    fn test_midnight_in_dst_gap() {
+        // clocks in Santiago jump from 00:00 to 01:00 on 2023-09-03
+        let periods = MarketPeriods::from_query(
+            Some("America/Santiago"),
+            None,
+            NaiveDate::from_ymd_opt(2023, 9, 3),
+        )
+        .unwrap()
+        .unwrap();
+        let (start, end) = periods.delivery_bounds().unwrap();
+        assert_eq!(start, Utc.with_ymd_and_hms(2023, 9, 3, 4, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2023, 9, 4, 3, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_half_hour_offset_alignment() {
+        let periods = MarketPeriods::from_query(Some("Asia/Kolkata"), Some(60), None)
+            .unwrap()
+            .unwrap();
+        let aligned = periods.align(aggregation("2023-05-01T00:00:00Z", 4));
+        assert!(aligned
+            .series
+            .iter()
+            .all(|dt| dt.time.format("%M").to_string() == "30"));
+    }
+
+    #[test]
+    fn test_invalid_market_periods() {
+        assert!(MarketPeriods::from_query(Some("Mars/Olympus"), None, None).is_err());
+        assert!(MarketPeriods::from_query(None, Some(20), None).is_err());
+        assert!(MarketPeriods::from_query(None, None, None)
+            .unwrap()
+            .is_none());
+    }
+}
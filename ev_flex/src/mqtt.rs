@@ -0,0 +1,86 @@
+use crate::config::MqttConfig;
+use crate::demand::{Demands, EnergyDemand};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_PACKET_SIZE: usize = 1 << 20;
+
+/// Stores a demand published on MQTT, validated like `POST /demand`.
+pub fn ingest(db: &Demands, payload: &[u8]) -> Result<String, String> {
+    let demand: EnergyDemand = serde_json::from_slice(payload).map_err(|err| err.to_string())?;
+    demand.validate()?;
+    let vehicle_id = demand.vehicle_id.clone();
+    Ok(match db.insert(demand) {
+        Some(_) => format!("Updated demand for {}!", vehicle_id),
+        None => format!("Received demand for {}!", vehicle_id),
+    })
+}
+
+pub fn connect(config: &MqttConfig) -> (AsyncClient, EventLoop) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    // envelopes of long sessions exceed the default limit of 10 kB
+    options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    AsyncClient::new(options, 64)
+}
+
+/// Ingests demands from the demand topics and publishes the fleet envelope as
+/// a retained message after every change of the store, however it was made.
+/// Reconnects after connection errors and returns once the store stops
+/// notifying its listeners.
+pub async fn run(db: Arc<Demands>, config: MqttConfig) {
+    let (client, mut eventloop) = connect(&config);
+    let (sender, receiver) = channel();
+    db.add_listener(sender);
+    let (publisher, store) = (client.clone(), Arc::downgrade(&db));
+    let aggregation_topic = config.aggregation_topic.clone();
+    // the envelope is computed off the executor, once for a burst of changes
+    let mut changes = tokio::task::spawn_blocking(move || {
+        while receiver.recv().is_ok() {
+            while receiver.try_recv().is_ok() {}
+            let Some(store) = store.upgrade() else {
+                return;
+            };
+            if let Some(topic) = &aggregation_topic {
+                let payload = serde_json::to_vec(&store.aggregation()).unwrap();
+                // requests only queue up in the client, the event loop sends them
+                if let Err(err) = publisher.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+                    eprintln!("Could not publish the aggregation: {}", err);
+                }
+            }
+        }
+    });
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = &mut changes => return,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // subscriptions do not survive a new session
+                for topic in &config.demand_topics {
+                    if let Err(err) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                        eprintln!("Could not subscribe to {}: {}", topic, err);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                // storing computes the series of the vehicle, which blocks
+                let db = db.clone();
+                let payload = publish.payload.clone();
+                match tokio::task::spawn_blocking(move || ingest(&db, &payload)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => eprintln!("Rejected demand on {}: {}", publish.topic, err),
+                    Err(err) => eprintln!("Could not ingest demand on {}: {}", publish.topic, err),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("MQTT connection error: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
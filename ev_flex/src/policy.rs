@@ -0,0 +1,155 @@
+use crate::demand::EnergyDemand;
+use serde::{Deserialize, Serialize};
+
+/// Battery protection limits a vehicle or fleet owner puts on flexible charging.
+///
+/// Charging is one-directional here, so every limit ends up capping how far the
+/// state of charge may rise in a session, or raising the level that must be kept.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DegradationPolicy {
+    pub max_daily_throughput: Option<i32>, // maximum energy charged per day in Wh
+    pub comfort_min_soc: Option<i32>,      // state of charge to reach asap in percent
+    pub comfort_max_soc: Option<i32>,      // state of charge not to exceed in percent
+    pub max_cycle_depth: Option<i32>,      // maximum state of charge swing in a session in percent
+}
+
+impl DegradationPolicy {
+    /// Checks the policy for limits no battery can have.
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .max_daily_throughput
+            .is_some_and(|throughput| throughput < 0)
+        {
+            return Err("max_daily_throughput must not be negative".to_string());
+        }
+        let percentages = [
+            self.comfort_min_soc,
+            self.comfort_max_soc,
+            self.max_cycle_depth,
+        ];
+        if percentages
+            .iter()
+            .flatten()
+            .any(|soc| !(0..=100).contains(soc))
+        {
+            return Err("policy limits must be between 0 and 100 percent".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.comfort_min_soc, self.comfort_max_soc) {
+            if min > max {
+                return Err("comfort_min_soc must not be above comfort_max_soc".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Target and minimum state of charge of the demand in percent once the policy applies.
+    pub fn limits(&self, demand: &EnergyDemand) -> (i32, i32) {
+        // in i64, so that limits of unvalidated policies cannot overflow
+        let current_soc = demand.current_soc as i64;
+        let mut target_soc = demand.target_soc as i64;
+        if let Some(throughput) = self.max_daily_throughput {
+            // every started day of the session allows another day's throughput
+            let minutes = (demand.end - demand.start).num_minutes().max(1);
+            let days = minutes.unsigned_abs().div_ceil(24 * 60) as i64;
+            let throughput_soc = throughput as i64 * days * 100 / (demand.capacity as i64).max(1);
+            target_soc = target_soc.min((current_soc + throughput_soc).min(100));
+        }
+        if let Some(depth) = self.max_cycle_depth {
+            target_soc = target_soc.min(current_soc + depth as i64);
+        }
+        if let Some(comfort_max_soc) = self.comfort_max_soc {
+            target_soc = target_soc.min((comfort_max_soc as i64).max(current_soc));
+        }
+        let target_soc = target_soc.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        let min_soc = demand.min_soc.max(self.comfort_min_soc.unwrap_or(0));
+        (target_soc, min_soc.min(target_soc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::EnergyDemand;
+    use crate::policy::DegradationPolicy;
+    use chrono::Duration;
+
+    #[test]
+    fn test_policy_limits() {
+        let demand = EnergyDemand {
+            target_soc: 90,
+            ..EnergyDemand::example("a")
+        };
+        assert_eq!(DegradationPolicy::default().limits(&demand), (90, 20));
+
+        let policy = DegradationPolicy {
+            max_daily_throughput: Some(30000),
+            ..Default::default()
+        };
+        assert_eq!(policy.limits(&demand), (60, 20));
+        // a full day is still one day, a minute more starts the second
+        let mut day_long = demand.clone();
+        day_long.end = day_long.start + Duration::hours(24);
+        assert_eq!(policy.limits(&day_long), (60, 20));
+        day_long.end += Duration::minutes(1);
+        assert_eq!(policy.limits(&day_long), (90, 20));
+
+        let policy = DegradationPolicy {
+            comfort_min_soc: Some(30),
+            comfort_max_soc: Some(80),
+            max_cycle_depth: Some(75),
+            ..Default::default()
+        };
+        assert_eq!(policy.limits(&demand), (80, 30));
+
+        let policy = DegradationPolicy {
+            max_cycle_depth: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(policy.limits(&demand), (15, 15));
+
+        // unvalidated policies must not overflow
+        let policy = DegradationPolicy {
+            max_daily_throughput: Some(i32::MAX),
+            max_cycle_depth: Some(i32::MAX),
+            ..Default::default()
+        };
+        assert_eq!(policy.limits(&demand), (90, 20));
+    }
+
+    #[test]
+    fn test_policy_validate() {
+        assert!(DegradationPolicy::default().validate().is_ok());
+        let invalid = [
+            DegradationPolicy {
+                max_cycle_depth: Some(i32::MAX),
+                ..Default::default()
+            },
+            DegradationPolicy {
+                comfort_min_soc: Some(-1),
+                ..Default::default()
+            },
+            DegradationPolicy {
+                max_daily_throughput: Some(-1),
+                ..Default::default()
+            },
+            DegradationPolicy {
+                comfort_min_soc: Some(60),
+                comfort_max_soc: Some(50),
+                ..Default::default()
+            },
+        ];
+        for policy in invalid {
+            assert!(policy.validate().is_err(), "{:?}", policy);
+        }
+
+        // the comfort maximum of a demand's own policy has to keep its minimum
+        let demand = EnergyDemand {
+            degradation_policy: Some(DegradationPolicy {
+                comfort_max_soc: Some(10),
+                ..Default::default()
+            }),
+            ..EnergyDemand::example("a")
+        };
+        assert!(demand.validate().is_err());
+    }
+}
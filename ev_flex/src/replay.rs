@@ -0,0 +1,249 @@
+use crate::aggregation::Aggregation;
+use crate::demand::{Demands, EnergyDemand};
+use crate::envelope::VehicleEnvelope;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Reads recorded demands, as CSV for `.csv` files and JSON lines otherwise.
+pub fn read_demands(path: &Path) -> io::Result<Vec<EnergyDemand>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        let mut reader = csv::Reader::from_reader(file);
+        return reader
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::from);
+    }
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum Event {
+    Departure(usize),
+    Arrival(usize),
+}
+
+#[derive(Serialize)]
+pub struct ReplayStep {
+    pub time: DateTime<Utc>,
+    pub active_vehicles: usize,
+    pub infeasible_vehicles: usize,
+    pub aggregation: Aggregation,
+}
+
+#[derive(Serialize, Default)]
+pub struct ReplayStatistics {
+    pub demands: usize,
+    pub invalid_demands: usize, // records left out for failing validation
+    pub infeasible_demands: usize,
+    pub steps: usize,
+    pub peak_active_vehicles: usize,
+    pub peak_max_charging_power: i64,
+    pub peak_baseline_power: i64,
+    pub max_upward_flex: i64,
+    pub max_downward_flex: i64,
+}
+
+impl ReplayStatistics {
+    /// Statistics of the demands of `replay`, before any step is recorded.
+    pub fn new(replay: &Replay) -> Self {
+        ReplayStatistics {
+            demands: replay.demands.len(),
+            invalid_demands: replay.invalid_demands,
+            infeasible_demands: replay
+                .feasible
+                .iter()
+                .filter(|feasible| !**feasible)
+                .count(),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, step: &ReplayStep) {
+        let aggregation = &step.aggregation;
+        self.steps += 1;
+        self.peak_active_vehicles = self.peak_active_vehicles.max(step.active_vehicles);
+        // the envelope also covers the past of active sessions, only look at the clock
+        if let Some(dt) = aggregation.series.iter().find(|dt| dt.time >= step.time) {
+            self.peak_max_charging_power = self.peak_max_charging_power.max(dt.max_charging_power);
+            self.peak_baseline_power = self.peak_baseline_power.max(dt.baseline_power);
+        }
+        if let Some(flex) = aggregation
+            .flexibility
+            .iter()
+            .find(|flex| flex.time >= step.time)
+        {
+            self.max_upward_flex = self.max_upward_flex.max(flex.upward_flex);
+            self.max_downward_flex = self.max_downward_flex.max(flex.downward_flex);
+        }
+    }
+}
+
+/// Replays recorded demands against a simulated clock.
+///
+/// Every demand is submitted at its `start` and removed at its `end`; after
+/// applying all events up to the clock, each step yields the fleet envelope as
+/// the server would have published it at that time. Records the server would
+/// have rejected are left out.
+pub struct Replay {
+    demands: Vec<EnergyDemand>,
+    invalid_demands: usize,
+    // whether the target of each demand can be reached in time
+    feasible: Vec<bool>,
+    events: Vec<(DateTime<Utc>, Event)>,
+    next_event: usize,
+    store: Demands,
+    // start of the session currently stored per vehicle, so that a departure
+    // does not remove a later session of the same vehicle
+    sessions: HashMap<String, DateTime<Utc>>,
+    infeasible: HashSet<String>,
+    clock: DateTime<Utc>,
+    end: DateTime<Utc>,
+    step: Duration,
+}
+
+impl Replay {
+    /// Panics if `step` is not positive, as the clock would never advance.
+    pub fn new(demands: Vec<EnergyDemand>, step: Duration) -> Self {
+        assert!(step > Duration::zero(), "replay step must be positive");
+        let records = demands.len();
+        let demands: Vec<EnergyDemand> = demands
+            .into_iter()
+            .filter(|demand| demand.validate().is_ok())
+            .collect();
+        let feasible = demands
+            .iter()
+            .map(|demand| VehicleEnvelope::from_demand(demand).is_some())
+            .collect();
+        let mut events: Vec<(DateTime<Utc>, Event)> = demands
+            .iter()
+            .enumerate()
+            .flat_map(|(i, demand)| {
+                [
+                    (demand.start, Event::Arrival(i)),
+                    (demand.end, Event::Departure(i)),
+                ]
+            })
+            .collect();
+        // departures first, so that back-to-back sessions do not overlap
+        events.sort_by_key(|(time, event)| (*time, matches!(event, Event::Arrival(_))));
+        let clock = events
+            .first()
+            .map(|(time, _)| *time)
+            .unwrap_or_else(Utc::now);
+        let end = events.last().map(|(time, _)| *time).unwrap_or(clock);
+        Replay {
+            invalid_demands: records - demands.len(),
+            demands,
+            feasible,
+            events,
+            next_event: 0,
+            store: Demands::new(),
+            sessions: HashMap::new(),
+            infeasible: HashSet::new(),
+            clock,
+            end,
+            step,
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Arrival(i) => {
+                let demand = &self.demands[i];
+                self.sessions
+                    .insert(demand.vehicle_id.clone(), demand.start);
+                match self.feasible[i] {
+                    true => self.infeasible.remove(&demand.vehicle_id),
+                    false => self.infeasible.insert(demand.vehicle_id.clone()),
+                };
+                self.store.insert(demand.clone());
+            }
+            Event::Departure(i) => {
+                let demand = &self.demands[i];
+                if self.sessions.get(&demand.vehicle_id) == Some(&demand.start) {
+                    self.sessions.remove(&demand.vehicle_id);
+                    self.infeasible.remove(&demand.vehicle_id);
+                    self.store.remove(&demand.vehicle_id);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Replay {
+    type Item = ReplayStep;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.clock > self.end {
+            return None;
+        }
+        while self.next_event < self.events.len() && self.events[self.next_event].0 <= self.clock {
+            let (_, event) = self.events[self.next_event];
+            self.apply(event);
+            self.next_event += 1;
+        }
+        let step = ReplayStep {
+            time: self.clock,
+            active_vehicles: self.sessions.len(),
+            infeasible_vehicles: self.infeasible.len(),
+            aggregation: self.store.aggregation(),
+        };
+        self.clock += self.step;
+        Some(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::EnergyDemand;
+    use crate::replay::{Replay, ReplayStatistics};
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_replay_steps() {
+        // "b" arrives as "a" leaves and cannot reach its target
+        let a = EnergyDemand::example("a");
+        let b = EnergyDemand {
+            start: a.end,
+            end: a.end + Duration::hours(1),
+            ..EnergyDemand::example("b")
+        };
+        let replay = Replay::new(vec![b, a], Duration::hours(6));
+        let mut statistics = ReplayStatistics::new(&replay);
+        let steps: Vec<_> = replay.inspect(|step| statistics.record(step)).collect();
+        let active: Vec<_> = steps.iter().map(|step| step.active_vehicles).collect();
+        assert_eq!(active, [1, 1, 1]);
+        assert_eq!(steps[2].infeasible_vehicles, 1);
+        assert_eq!(statistics.steps, 3);
+        assert_eq!(statistics.infeasible_demands, 1);
+        assert_eq!(statistics.peak_max_charging_power, 11000);
+    }
+
+    #[test]
+    fn test_replay_skips_invalid_records() {
+        let valid = EnergyDemand::example("a");
+        let reversed = EnergyDemand {
+            end: Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap(),
+            ..EnergyDemand::example("b")
+        };
+        let overcharged = EnergyDemand {
+            target_soc: 120,
+            ..EnergyDemand::example("c")
+        };
+        let replay = Replay::new(vec![valid, reversed, overcharged], Duration::hours(6));
+        let statistics = ReplayStatistics::new(&replay);
+        assert_eq!((statistics.demands, statistics.invalid_demands), (1, 2));
+        // a session ending before it starts would never have departed
+        let last = replay.last().unwrap();
+        assert_eq!(last.active_vehicles, 0);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::aggregation::{Aggregation, AggregationDT, AGGREGATION_FREQ_MINUTES};
+use crate::market::MarketPeriods;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReserveProduct {
+    Fcr,
+    Afrr,
+}
+
+impl ReserveProduct {
+    /// Whether the same power has to be offered in both directions.
+    pub fn symmetric(&self) -> bool {
+        matches!(self, ReserveProduct::Fcr)
+    }
+
+    /// Minutes a full activation has to be sustained.
+    pub fn default_duration_minutes(&self) -> i64 {
+        match self {
+            ReserveProduct::Fcr => 15,
+            ReserveProduct::Afrr => 60,
+        }
+    }
+
+    /// Length of a market block in minutes.
+    pub fn default_block_minutes(&self) -> u32 {
+        240
+    }
+}
+
+/// Reserve power the fleet can hold throughout one market block.
+///
+/// Upward reserve supports the grid by charging less than the baseline,
+/// downward reserve by charging more.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReserveBlock {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub upward: i64,   // reserve power below baseline charging in W
+    pub downward: i64, // reserve power above baseline charging in W
+    pub offered: i64,  // symmetric power for symmetric products, else the larger direction in W
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReserveQualification {
+    pub product: ReserveProduct,
+    pub duration_minutes: i64,
+    pub block_minutes: u32,
+    pub blocks: Vec<ReserveBlock>,
+}
+
+fn minutes(from: &AggregationDT, to: &AggregationDT) -> i64 {
+    (to.time - from.time).num_minutes()
+}
+
+// Reserve power in both directions that can be activated at `start` and held
+// for `duration` minutes without leaving the envelope, None if the series ends sooner
+// or the duration is not positive.
+fn sustainable_reserve(
+    series: &[AggregationDT],
+    start: usize,
+    duration: i64,
+) -> Option<(i64, i64)> {
+    if duration < 1 {
+        return None;
+    }
+    let origin = &series[start];
+    let mut upward = i64::MAX;
+    let mut downward = i64::MAX;
+    for window in series[start..].windows(2) {
+        let (current, next) = (&window[0], &window[1]);
+        if minutes(origin, current) >= duration {
+            return Some((upward, downward));
+        }
+        upward = upward.min(current.baseline_power);
+        downward = downward.min(current.max_charging_power - current.baseline_power);
+        let elapsed = minutes(origin, next).min(duration);
+        upward = upward.min((next.baseline_soe - next.min_soe) * 60 / elapsed);
+        downward = downward.min((next.max_soe - next.baseline_soe) * 60 / elapsed);
+    }
+    // the series ended, which is only enough if it covered the whole duration
+    match series.last() {
+        Some(last) if minutes(origin, last) >= duration => Some((upward, downward)),
+        _ => None,
+    }
+}
+
+/// Reserve that can be offered in each market block fully covered by the aggregation.
+///
+/// Activation may start at any interval of a block, so each block offers the
+/// least reserve sustainable from any of its intervals.
+pub fn qualify(
+    aggregation: &Aggregation,
+    product: ReserveProduct,
+    duration_minutes: i64,
+    periods: &MarketPeriods,
+) -> ReserveQualification {
+    let series = &aggregation.series;
+    let block_starts: Vec<usize> = (0..series.len())
+        .filter(|i| periods.is_period_start(series[*i].time))
+        .collect();
+    let blocks = block_starts
+        .windows(2)
+        .filter_map(|window| {
+            let (first, last) = (window[0], window[1]);
+            // blocks with gaps in the envelope are not covered by the fleet
+            let continuous = series[first..=last]
+                .windows(2)
+                .all(|w| minutes(&w[0], &w[1]) == AGGREGATION_FREQ_MINUTES as i64);
+            if !continuous {
+                return None;
+            }
+            let mut upward = i64::MAX;
+            let mut downward = i64::MAX;
+            for i in first..last {
+                let (up, down) = sustainable_reserve(series, i, duration_minutes)?;
+                upward = upward.min(up);
+                downward = downward.min(down);
+            }
+            let (upward, downward) = (upward.max(0), downward.max(0));
+            Some(ReserveBlock {
+                start: series[first].time,
+                end: series[last].time,
+                upward,
+                downward,
+                offered: match product.symmetric() {
+                    true => upward.min(downward),
+                    false => upward.max(downward),
+                },
+            })
+        })
+        .collect();
+    ReserveQualification {
+        product,
+        duration_minutes,
+        block_minutes: periods.period_minutes,
+        blocks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::{Aggregation, AggregationDT};
+    use crate::market::MarketPeriods;
+    use crate::reserve::{qualify, ReserveProduct};
+    use chrono::{Duration, TimeZone, Utc};
+
+    // charges 4 kWh in the first hour and may charge up to 8 kWh from 30 minutes on
+    fn aggregation() -> Aggregation {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+        let series = (0..=16)
+            .map(|i| AggregationDT {
+                min_soe: 0,
+                max_soe: match i {
+                    0 => 0,
+                    1 => 1000,
+                    _ => 8000,
+                },
+                max_charging_power: 4000,
+                baseline_soe: 1000 * i.min(4),
+                baseline_power: if i < 4 { 4000 } else { 0 },
+                time: start + Duration::minutes(15 * i),
+                local_time: None,
+            })
+            .collect();
+        Aggregation {
+            start,
+            end: start + Duration::hours(4),
+            series,
+            flexibility: vec![],
+        }
+    }
+
+    #[test]
+    fn test_qualify_blocks() {
+        let periods = MarketPeriods::new(None, 60, None).unwrap();
+        let afrr = qualify(&aggregation(), ReserveProduct::Afrr, 15, &periods);
+        assert_eq!(afrr.blocks.len(), 4);
+        assert_eq!(afrr.blocks[0].upward, 4000);
+        assert_eq!(afrr.blocks[0].downward, 0);
+        assert_eq!(afrr.blocks[0].offered, 4000);
+        assert_eq!(afrr.blocks[1].upward, 0);
+        assert_eq!(afrr.blocks[1].downward, 4000);
+
+        let fcr = qualify(&aggregation(), ReserveProduct::Fcr, 15, &periods);
+        assert!(fcr.blocks.iter().all(|block| block.offered == 0));
+    }
+
+    #[test]
+    fn test_qualify_duration_beyond_series() {
+        let periods = MarketPeriods::new(None, 240, None).unwrap();
+        let fcr = qualify(&aggregation(), ReserveProduct::Fcr, 15, &periods);
+        assert_eq!(fcr.blocks.len(), 1);
+        let long = qualify(&aggregation(), ReserveProduct::Fcr, 60, &periods);
+        assert!(long.blocks.is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+use crate::demand::{Demands, EnergyDemand};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::RwLock;
+
+/// Charging session that repeats on the same weekdays, such as a bus that is
+/// back at the depot every weekday evening.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RecurringDemand {
+    pub vehicle_id: String,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>, example = json!(["Mon", "Tue", "Wed", "Thu", "Fri"])))]
+    pub weekdays: Vec<Weekday>, // days the vehicle arrives on
+    pub arrival: NaiveTime,   // local arrival time
+    pub departure: NaiveTime, // local departure time, on the next day if not after arrival
+    #[serde(default)]
+    pub timezone: Option<String>, // timezone of the times, UTC if none is given
+    pub expected_soc: i32,    // expected state of charge on arrival in percent
+    pub min_soc: i32,         // minimum state of charge in percent
+    pub max_soc: i32,         // maximum state of charge in percent
+    pub target_soc: i32,      // target state of charge in percent
+    pub capacity: i32,        // capacity in Wh
+    pub max_charging_power: i32, // maximum charging power in W
+    #[serde(default)]
+    pub valid_from: Option<NaiveDate>, // first day a session may arrive on
+    #[serde(default)]
+    pub valid_until: Option<NaiveDate>, // last day a session may arrive on
+    #[serde(default)]
+    pub fleet: Option<String>,
+    #[serde(default)]
+    pub charger_type: Option<String>,
+    #[serde(default)]
+    pub grid_node: Option<String>,
+    #[serde(default)]
+    pub customer: Option<String>,
+}
+
+/// Vehicle id of the session of `vehicle_id` that arrives on the local `date`.
+pub fn session_id(vehicle_id: &str, date: NaiveDate) -> String {
+    format!("{}@{}", vehicle_id, date)
+}
+
+/// Vehicle a stored demand belongs to, which for expanded sessions is the
+/// vehicle of their template.
+pub fn session_vehicle(vehicle_id: &str) -> &str {
+    vehicle_id.split('@').next().unwrap_or(vehicle_id)
+}
+
+/// UTC time of a local time; times in a DST gap are moved to the gap's end,
+/// the first minute that exists locally.
+pub(crate) fn to_utc(timezone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    (0..24 * 60)
+        .map(|minutes| local + Duration::minutes(minutes))
+        .find_map(|local| timezone.from_local_datetime(&local).earliest())
+        .expect("a DST gap is shorter than a day")
+        .with_timezone(&Utc)
+}
+
+impl RecurringDemand {
+    fn timezone(&self) -> Result<Tz, String> {
+        match &self.timezone {
+            Some(name) => name
+                .parse()
+                .map_err(|_| format!("Unknown timezone {}!", name)),
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    /// Checks the template for values no session can have.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.weekdays.is_empty() {
+            return Err("weekdays must not be empty".to_string());
+        }
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until) {
+            if from > until {
+                return Err("valid_from must not be after valid_until".to_string());
+            }
+        }
+        let timezone = self.timezone()?;
+        let date = NaiveDate::from_ymd_opt(2000, 1, 3).unwrap();
+        match self.session(timezone, date) {
+            // checked under the id of the template, as session ids contain @
+            Some(session) => EnergyDemand {
+                vehicle_id: self.vehicle_id.clone(),
+                ..session
+            }
+            .validate(),
+            None => Err("arrival and departure must be valid local times".to_string()),
+        }
+    }
+
+    // session arriving on the local `date`
+    fn session(&self, timezone: Tz, date: NaiveDate) -> Option<EnergyDemand> {
+        let departure_date = match self.departure > self.arrival {
+            true => date,
+            false => date.succ_opt()?,
+        };
+        Some(EnergyDemand {
+            vehicle_id: session_id(&self.vehicle_id, date),
+            min_soc: self.min_soc,
+            max_soc: self.max_soc,
+            target_soc: self.target_soc,
+            current_soc: self.expected_soc,
+            capacity: self.capacity,
+            max_charging_power: self.max_charging_power,
+            start: to_utc(timezone, date.and_time(self.arrival)),
+            end: to_utc(timezone, departure_date.and_time(self.departure)),
+            fleet: self.fleet.clone(),
+            charger_type: self.charger_type.clone(),
+            grid_node: self.grid_node.clone(),
+            customer: self.customer.clone(),
+            degradation_policy: None,
+            departure_distribution: None,
+        })
+    }
+
+    /// Sessions that are at the depot at some time between `from` and `to`.
+    pub fn sessions(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<EnergyDemand> {
+        let Ok(timezone) = self.timezone() else {
+            return vec![];
+        };
+        // a session may have arrived the day before `from`
+        let first = from.with_timezone(&timezone).date_naive() - Duration::days(1);
+        let last = to.with_timezone(&timezone).date_naive();
+        first
+            .iter_days()
+            .take_while(|date| *date <= last)
+            .filter(|date| self.weekdays.contains(&date.weekday()))
+            .filter(|date| self.valid_from.is_none_or(|from| *date >= from))
+            .filter(|date| self.valid_until.is_none_or(|until| *date <= until))
+            .filter_map(|date| self.session(timezone, date))
+            .filter(|session| session.end > from && session.start < to)
+            .collect()
+    }
+}
+
+/// Recurring demands, expanded into the demands of their sessions over the
+/// next `horizon`.
+///
+/// Expanded sessions are stored like any other demand under their
+/// [`session_id`], so that the aggregation covers vehicles before they arrive.
+/// A demand submitted under the vehicle id itself replaces the sessions it
+/// overlaps at the next refresh.
+pub struct Schedules {
+    pub(crate) templates: RwLock<BTreeMap<String, RecurringDemand>>,
+    horizon: Duration,
+}
+
+impl Schedules {
+    pub fn new(horizon: Duration) -> Self {
+        Schedules {
+            templates: RwLock::default(),
+            horizon,
+        }
+    }
+
+    pub fn templates(&self) -> Vec<RecurringDemand> {
+        self.templates.read().unwrap().values().cloned().collect()
+    }
+
+    /// Stores the template, replacing the sessions of any previous one.
+    pub fn insert(
+        &self,
+        db: &Demands,
+        template: RecurringDemand,
+        now: DateTime<Utc>,
+    ) -> Option<RecurringDemand> {
+        let vehicle_id = template.vehicle_id.clone();
+        let previous = self
+            .templates
+            .write()
+            .unwrap()
+            .insert(vehicle_id.clone(), template);
+        if previous.is_some() {
+            Self::remove_sessions(db, &vehicle_id, |_| true);
+        }
+        self.refresh(db, now);
+        previous
+    }
+
+    /// Removes the template and the sessions expanded from it.
+    pub fn remove(&self, db: &Demands, vehicle_id: &str) -> Option<RecurringDemand> {
+        let previous = self.templates.write().unwrap().remove(vehicle_id);
+        if previous.is_some() {
+            Self::remove_sessions(db, vehicle_id, |_| true);
+        }
+        previous
+    }
+
+    // removes the stored sessions of the vehicle that `remove` selects
+    fn remove_sessions(db: &Demands, vehicle_id: &str, remove: impl Fn(&str) -> bool) {
+        let prefix = format!("{}@", vehicle_id);
+        let stored = db
+            .vehicle_ids()
+            .into_iter()
+            .filter(|id| id.starts_with(&prefix) && remove(id));
+        for id in stored {
+            db.remove(&id);
+        }
+    }
+
+    /// Expands every template over the horizon from `now`, dropping sessions
+    /// that have left, overlap a submitted demand, or no longer follow from
+    /// their template. Returns the number of sessions added.
+    pub fn refresh(&self, db: &Demands, now: DateTime<Utc>) -> usize {
+        let mut added = vec![];
+        for template in self.templates.read().unwrap().values() {
+            let submitted = db.get(&template.vehicle_id);
+            let sessions: Vec<EnergyDemand> = template
+                .sessions(now, now + self.horizon)
+                .into_iter()
+                .filter(|session| match &submitted {
+                    Some(demand) => session.end <= demand.start || session.start >= demand.end,
+                    None => true,
+                })
+                .collect();
+            let wanted: HashSet<&str> = sessions.iter().map(|s| s.vehicle_id.as_str()).collect();
+            Self::remove_sessions(db, &template.vehicle_id, |id| !wanted.contains(id));
+            added.extend(
+                sessions
+                    .into_iter()
+                    .filter(|session| !db.contains(&session.vehicle_id)),
+            );
+        }
+        let count = added.len();
+        db.insert_many(added);
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::Demands;
+    use crate::schedule::{RecurringDemand, Schedules};
+    use chrono::{Duration, NaiveTime, TimeZone, Utc, Weekday};
+
+    fn weekday_evenings() -> RecurringDemand {
+        RecurringDemand {
+            vehicle_id: "bus".to_string(),
+            weekdays: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            arrival: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            departure: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            timezone: Some("Europe/Berlin".to_string()),
+            expected_soc: 30,
+            min_soc: 20,
+            max_soc: 100,
+            target_soc: 80,
+            capacity: 300000,
+            max_charging_power: 150000,
+            valid_from: None,
+            valid_until: None,
+            fleet: None,
+            charger_type: None,
+            grid_node: None,
+            customer: None,
+        }
+    }
+
+    #[test]
+    fn test_recurring_sessions() {
+        let template = weekday_evenings();
+        assert!(template.validate().is_ok());
+
+        // Friday noon: the Friday session, then nothing over the weekend
+        let friday = Utc.with_ymd_and_hms(2023, 5, 5, 12, 0, 0).unwrap();
+        let sessions = template.sessions(friday, friday + Duration::days(4));
+        let ids: Vec<&str> = sessions.iter().map(|s| s.vehicle_id.as_str()).collect();
+        assert_eq!(ids, ["bus@2023-05-05", "bus@2023-05-08"]);
+        // 18:00 in Berlin is 16:00 UTC in summer
+        assert_eq!(
+            sessions[0].start,
+            Utc.with_ymd_and_hms(2023, 5, 5, 16, 0, 0).unwrap()
+        );
+        assert_eq!(
+            sessions[0].end,
+            Utc.with_ymd_and_hms(2023, 5, 6, 4, 0, 0).unwrap()
+        );
+        assert_eq!(sessions[0].current_soc, 30);
+
+        let schedules = Schedules::new(Duration::days(4));
+        let db = Demands::new();
+        schedules.insert(&db, template, friday);
+        assert_eq!(db.len(), 2);
+
+        // the bus arrives and submits its own demand for Friday evening
+        let mut submitted = sessions[0].clone();
+        submitted.vehicle_id = "bus".to_string();
+        db.insert(submitted);
+        assert_eq!(schedules.refresh(&db, friday), 0);
+        assert_eq!(db.vehicle_ids(), ["bus", "bus@2023-05-08"]);
+
+        schedules.remove(&db, "bus");
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_arrival_in_dst_gap() {
+        let template = RecurringDemand {
+            weekdays: vec![Weekday::Sun],
+            arrival: NaiveTime::from_hms_opt(2, 30, 0).unwrap(),
+            ..weekday_evenings()
+        };
+        let saturday = Utc.with_ymd_and_hms(2023, 3, 25, 12, 0, 0).unwrap();
+        let sessions = template.sessions(saturday, saturday + Duration::days(1));
+        // clocks in Berlin go from 02:00 to 03:00, the session starts at 03:00
+        assert_eq!(
+            sessions[0].start,
+            Utc.with_ymd_and_hms(2023, 3, 26, 1, 0, 0).unwrap()
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use crate::demand::{Demands, EnergyDemand, SocReading};
+use crate::envelope::{PiecewiseLinear, VehicleEnvelope};
+use crate::schedule::session_vehicle;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementLevel {
+    #[default]
+    Session,
+    Day,
+}
+
+/// Plan and outcome of a charging session that has ended.
+///
+/// ev_flex does not dispatch schedules of its own, so the planned energy is
+/// that of the baseline schedule, charging at full power until the target.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SessionSettlement {
+    pub vehicle_id: String,
+    pub day: NaiveDate, // local day of arrival
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub feasible: bool, // whether an envelope was published for the session
+    pub envelope_min_energy: Option<i64>, // energy the envelope had to charge in Wh
+    pub planned_energy: Option<i64>, // energy of the baseline schedule in Wh
+    pub readings: usize, // state of charge readings during the session
+    pub final_soc: Option<i32>, // last measured state of charge in percent
+    pub measured_energy: Option<i64>, // energy charged according to the readings in Wh
+    pub deviation: Option<i64>, // measured minus planned energy in Wh
+    pub target_soc: i32,
+    pub target_reached: Option<bool>, // unknown without readings
+}
+
+/// Settlement of the sessions that arrived on a day.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DaySettlement {
+    pub day: NaiveDate,
+    pub sessions: usize,
+    pub infeasible: usize,
+    pub planned_energy: i64,          // Wh
+    pub measured_sessions: usize,     // sessions with readings
+    pub measured_energy: i64,         // Wh, of the sessions with readings
+    pub planned_energy_measured: i64, // planned Wh of the sessions with readings
+    pub targets_reached: usize,
+    pub targets_missed: usize,
+}
+
+// `demand` carries the policy it was published with
+fn settle_session(
+    demand: &EnergyDemand,
+    readings: &[SocReading],
+    timezone: Tz,
+) -> SessionSettlement {
+    let envelope = VehicleEnvelope::from_demand(demand);
+    // in whole percent steps of the capacity, as the envelope counts
+    let one_percent_energy = (demand.capacity / 100) as i64;
+    let arrival_soe = demand.current_soc as i64 * one_percent_energy;
+    let energy = |soe: &PiecewiseLinear| {
+        let end = soe.points().last()?.value.round() as i64;
+        Some(end - arrival_soe)
+    };
+    let planned_energy = envelope.as_ref().and_then(|e| energy(&e.max_soe));
+    let readings: Vec<&SocReading> = readings
+        .iter()
+        .filter(|r| r.time >= demand.start && r.time <= demand.end)
+        .collect();
+    let final_soc = readings.last().map(|r| r.soc);
+    let measured_energy =
+        final_soc.map(|soc| (soc - demand.current_soc) as i64 * one_percent_energy);
+    SessionSettlement {
+        vehicle_id: demand.vehicle_id.clone(),
+        day: demand.start.with_timezone(&timezone).date_naive(),
+        start: demand.start,
+        end: demand.end,
+        feasible: envelope.is_some(),
+        envelope_min_energy: envelope.as_ref().and_then(|e| energy(&e.min_soe)),
+        planned_energy,
+        readings: readings.len(),
+        final_soc,
+        measured_energy,
+        deviation: measured_energy.zip(planned_energy).map(|(m, p)| m - p),
+        target_soc: demand.target_soc,
+        target_reached: final_soc.map(|soc| soc >= demand.target_soc),
+    }
+}
+
+/// Settles the sessions that ended before `now` and arrived from `from` to
+/// `to` (inclusive) in `timezone`, ordered by arrival.
+///
+/// Expanded recurring sessions are plans rather than sessions, so only
+/// demands submitted for a vehicle are settled. Sessions are settled against
+/// the policy their envelope was last published with, so later changes of the
+/// fleet policy leave sessions that were replaced as they were.
+pub fn settle_sessions(
+    db: &Demands,
+    from: NaiveDate,
+    to: NaiveDate,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Vec<SessionSettlement> {
+    let mut sessions: Vec<SessionSettlement> = db
+        .history()
+        .into_iter()
+        .chain(
+            db.all()
+                .iter()
+                .map(|demand| db.published(demand).into_owned()),
+        )
+        .filter(|demand| demand.end <= now)
+        .filter(|demand| session_vehicle(&demand.vehicle_id) == demand.vehicle_id)
+        .filter(|demand| {
+            let day = demand.start.with_timezone(&timezone).date_naive();
+            from <= day && day <= to
+        })
+        .map(|demand| {
+            let readings = db.readings(&demand.vehicle_id);
+            settle_session(&demand, &readings, timezone)
+        })
+        .collect();
+    sessions.sort_by(|a, b| (a.start, &a.vehicle_id).cmp(&(b.start, &b.vehicle_id)));
+    sessions
+}
+
+/// Sums the sessions per day of arrival.
+pub fn settle_days(sessions: &[SessionSettlement]) -> Vec<DaySettlement> {
+    let mut days: BTreeMap<NaiveDate, DaySettlement> = BTreeMap::new();
+    for session in sessions {
+        let day = days.entry(session.day).or_insert(DaySettlement {
+            day: session.day,
+            sessions: 0,
+            infeasible: 0,
+            planned_energy: 0,
+            measured_sessions: 0,
+            measured_energy: 0,
+            planned_energy_measured: 0,
+            targets_reached: 0,
+            targets_missed: 0,
+        });
+        let planned = session.planned_energy.unwrap_or(0);
+        day.sessions += 1;
+        day.infeasible += !session.feasible as usize;
+        day.planned_energy += planned;
+        if let Some(measured) = session.measured_energy {
+            day.measured_sessions += 1;
+            day.measured_energy += measured;
+            day.planned_energy_measured += planned;
+        }
+        match session.target_reached {
+            Some(true) => day.targets_reached += 1,
+            Some(false) => day.targets_missed += 1,
+            None => {}
+        }
+    }
+    days.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::{Demands, EnergyDemand, SocReading};
+    use crate::policy::DegradationPolicy;
+    use crate::settlement::{settle_days, settle_sessions};
+    use chrono::{Duration, NaiveDate, TimeZone, Utc};
+    use chrono_tz::Tz;
+
+    fn demand(vehicle_id: &str, day: u32) -> EnergyDemand {
+        let start = Utc.with_ymd_and_hms(2023, 5, day, 18, 0, 0).unwrap();
+        EnergyDemand::example_session(vehicle_id, start, start + Duration::hours(12))
+    }
+
+    #[test]
+    fn test_settlement() {
+        let db = Demands::new();
+        let reading = |day, hour, soc| SocReading {
+            time: Utc.with_ymd_and_hms(2023, 5, day, hour, 0, 0).unwrap(),
+            soc,
+        };
+        db.insert(demand("a", 1));
+        db.insert(demand("b", 1));
+        assert!(db.add_reading("a", reading(1, 22, 60)));
+        assert!(db.add_reading("a", reading(2, 5, 80)));
+        assert!(db.add_reading("b", reading(2, 5, 70)));
+        assert!(!db.add_reading("c", reading(2, 5, 70)));
+        // the next session of "a" moves the first one to the history
+        db.insert(demand("a", 2));
+
+        let now = Utc.with_ymd_and_hms(2023, 5, 4, 0, 0, 0).unwrap();
+        let day = |d| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let sessions = settle_sessions(&db, day(1), day(2), Tz::UTC, now);
+        assert_eq!(sessions.len(), 3);
+        let (a, b) = (&sessions[0], &sessions[1]);
+        assert_eq!((a.vehicle_id.as_str(), b.vehicle_id.as_str()), ("a", "b"));
+        assert_eq!(a.planned_energy, Some(42000));
+        assert_eq!(a.envelope_min_energy, Some(42000));
+        assert_eq!((a.readings, a.final_soc), (2, Some(80)));
+        assert_eq!(a.measured_energy, Some(42000));
+        assert_eq!(a.target_reached, Some(true));
+        assert_eq!(b.deviation, Some(-6000));
+        assert_eq!(b.target_reached, Some(false));
+        assert_eq!(sessions[2].target_reached, None);
+
+        let days = settle_days(&sessions);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].sessions, 2);
+        assert_eq!(days[0].measured_energy, 78000);
+        assert_eq!((days[0].targets_reached, days[0].targets_missed), (1, 1));
+        assert_eq!(days[1].planned_energy, 42000);
+
+        // readings go with the vehicle
+        db.remove("b");
+        assert!(db.readings("b").is_empty());
+    }
+
+    #[test]
+    fn test_settlement_policy_at_submission() {
+        let db = Demands::new();
+        let comfort_max_soc = |soc| DegradationPolicy {
+            comfort_max_soc: Some(soc),
+            ..Default::default()
+        };
+        let in_depot = |vehicle_id, day| EnergyDemand {
+            fleet: Some("depot".to_string()),
+            ..demand(vehicle_id, day)
+        };
+        db.set_fleet_policy("depot", comfort_max_soc(50));
+        db.insert(in_depot("a", 1));
+        db.insert(in_depot("a", 2));
+        db.insert(in_depot("b", 2));
+        db.set_fleet_policy("depot", comfort_max_soc(70));
+
+        let now = Utc.with_ymd_and_hms(2023, 5, 4, 0, 0, 0).unwrap();
+        let day = |d| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let sessions = settle_sessions(&db, day(1), day(2), Tz::UTC, now);
+        // the first session of "a" ended under 50 percent, the stored
+        // sessions were republished with 70 percent of 60 kWh
+        let planned: Vec<_> = sessions.iter().map(|s| s.planned_energy).collect();
+        assert_eq!(planned, [Some(24000), Some(36000), Some(36000)]);
+        assert_eq!(
+            db.published(&db.get("b").unwrap()).degradation_policy,
+            Some(comfort_max_soc(70))
+        );
+    }
+
+    #[test]
+    fn test_settlement_large_battery() {
+        let db = Demands::new();
+        db.insert(EnergyDemand {
+            capacity: 2_000_000_050,
+            max_charging_power: 2_000_000_000,
+            ..demand("a", 1)
+        });
+        let reading = SocReading {
+            time: Utc.with_ymd_and_hms(2023, 5, 2, 5, 0, 0).unwrap(),
+            soc: 80,
+        };
+        assert!(db.add_reading("a", reading));
+
+        let now = Utc.with_ymd_and_hms(2023, 5, 4, 0, 0, 0).unwrap();
+        let day = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        let session = &settle_sessions(&db, day, day, Tz::UTC, now)[0];
+        // whole percent steps of the capacity, as in the envelope
+        assert_eq!(session.planned_energy, Some(1_400_000_000));
+        assert_eq!(session.measured_energy, Some(1_400_000_000));
+        assert_eq!(session.deviation, Some(0));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::demand::{Demands, EnergyDemand, SocReading};
+use crate::policy::DegradationPolicy;
+use crate::schedule::{RecurringDemand, Schedules};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Everything the store needs to continue after a restart.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Snapshot {
+    pub time: DateTime<Utc>,
+    pub demands: Vec<EnergyDemand>,
+    pub fleet_policies: BTreeMap<String, DegradationPolicy>,
+    pub history: Vec<EnergyDemand>, // replaced sessions, oldest first
+    pub readings: BTreeMap<String, Vec<SocReading>>,
+    pub templates: Vec<RecurringDemand>, // expanded sessions are among the demands
+}
+
+impl Snapshot {
+    pub fn of(db: &Demands, schedules: &Schedules) -> Self {
+        let fleet_policies = db.fleet_policies.read().unwrap().clone();
+        let readings = db.readings.read().unwrap();
+        Snapshot {
+            time: Utc::now(),
+            demands: db.all(),
+            fleet_policies: fleet_policies.into_iter().collect(),
+            history: db.history(),
+            readings: readings
+                .iter()
+                .map(|(vehicle_id, readings)| {
+                    (vehicle_id.clone(), readings.iter().cloned().collect())
+                })
+                .collect(),
+            templates: schedules.templates(),
+        }
+    }
+
+    /// Loads the snapshot into an empty store and empty schedules.
+    pub fn restore(self, db: &Demands, schedules: &Schedules) {
+        // policies first, so that the envelopes are computed and published with them
+        *db.fleet_policies.write().unwrap() =
+            self.fleet_policies.into_iter().collect::<HashMap<_, _>>();
+        db.insert_many(self.demands);
+        db.history.lock().unwrap().extend(self.history);
+        *schedules.templates.write().unwrap() = self
+            .templates
+            .into_iter()
+            .map(|template| (template.vehicle_id.clone(), template))
+            .collect();
+        let mut readings = db.readings.write().unwrap();
+        for (vehicle_id, vehicle_readings) in self.readings {
+            readings.insert(vehicle_id, vehicle_readings.into());
+        }
+    }
+
+    /// Writes the snapshot next to `path` and then moves it there, so that a
+    /// crash while writing never leaves a truncated snapshot behind.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer(&mut writer, self)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Reads the snapshot at `path`, None if there is none yet.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demand::{Demands, EnergyDemand, SocReading};
+    use crate::policy::DegradationPolicy;
+    use crate::schedule::{RecurringDemand, Schedules};
+    use crate::snapshot::Snapshot;
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::json;
+
+    fn demand(vehicle_id: &str, day: u32) -> EnergyDemand {
+        let start = Utc.with_ymd_and_hms(2023, 5, day, 18, 0, 0).unwrap();
+        EnergyDemand {
+            fleet: Some("depot".to_string()),
+            ..EnergyDemand::example_session(vehicle_id, start, start + Duration::hours(12))
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let db = Demands::new();
+        let policy = DegradationPolicy {
+            comfort_max_soc: Some(70),
+            ..Default::default()
+        };
+        db.set_fleet_policy("depot", policy.clone());
+        db.insert(demand("a", 1));
+        db.insert(demand("a", 2));
+        db.insert(demand("b", 2));
+        let reading = SocReading {
+            time: Utc.with_ymd_and_hms(2023, 5, 3, 5, 0, 0).unwrap(),
+            soc: 70,
+        };
+        db.add_reading("b", reading.clone());
+        let schedules = Schedules::new(Duration::days(1));
+        let template: RecurringDemand = serde_json::from_value(json!({
+            "vehicle_id": "bus",
+            "weekdays": ["Mon", "Tue", "Wed", "Thu", "Fri"],
+            "arrival": "18:00:00",
+            "departure": "06:00:00",
+            "expected_soc": 30,
+            "min_soc": 20,
+            "max_soc": 100,
+            "target_soc": 80,
+            "capacity": 300000,
+            "max_charging_power": 150000,
+        }))
+        .unwrap();
+        schedules.insert(
+            &db,
+            template,
+            Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap(),
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("ev_flex_snapshot_{}.json", std::process::id()));
+        Snapshot::of(&db, &schedules).write(&path).unwrap();
+        let restored = Demands::new();
+        let restored_schedules = Schedules::new(Duration::days(1));
+        Snapshot::read(&path)
+            .unwrap()
+            .unwrap()
+            .restore(&restored, &restored_schedules);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.vehicle_ids(), ["a", "b", "bus@2023-05-01"]);
+        assert_eq!(restored_schedules.templates().len(), 1);
+        assert_eq!(restored.history().len(), 1);
+        assert_eq!(restored.readings("b"), [reading]);
+        assert_eq!(
+            serde_json::to_value(restored.aggregation()).unwrap(),
+            serde_json::to_value(db.aggregation()).unwrap()
+        );
+        assert_eq!(
+            restored
+                .published(&restored.get("b").unwrap())
+                .degradation_policy,
+            Some(policy)
+        );
+        assert!(Snapshot::read(&path).unwrap().is_none());
+    }
+}
@@ -1,15 +1,142 @@
 use chrono::{DateTime, Duration, Utc};
 
-pub struct MinuteDateRange(pub DateTime<Utc>, pub DateTime<Utc>);
+/// Times from `start` up to `end` in steps of `step`, excluding `end` unless
+/// the range is made inclusive.
+///
+/// Iterates from either end, so `.rev()` walks the same times backwards.
+pub struct TimeRange {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    step: Duration,
+    inclusive: bool,
+    front: i64, // steps taken from the start
+    back: i64,  // steps taken from the end
+}
+
+impl TimeRange {
+    /// Half-open range `[start, end)`; `step` has to be at least a millisecond,
+    /// the resolution the range counts in.
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>, step: Duration) -> Self {
+        assert!(
+            step >= Duration::milliseconds(1),
+            "step must be at least a millisecond"
+        );
+        TimeRange {
+            start,
+            end,
+            step,
+            inclusive: false,
+            front: 0,
+            back: 0,
+        }
+    }
+
+    pub fn minutes(start: DateTime<Utc>, end: DateTime<Utc>, minutes: u32) -> Self {
+        Self::new(start, end, Duration::minutes(minutes as i64))
+    }
+
+    /// Closed range `[start, end]`.
+    pub fn inclusive(mut self) -> Self {
+        self.inclusive = true;
+        self
+    }
+
+    /// Starts at the first multiple of the step since the Unix epoch, so
+    /// ranges with the same step share their times.
+    pub fn aligned(mut self) -> Self {
+        let step = self.step.num_milliseconds();
+        let offset = self.start.timestamp_millis().rem_euclid(step);
+        if offset > 0 {
+            self.start += Duration::milliseconds(step - offset);
+        }
+        self
+    }
+
+    // number of times in the whole range, regardless of iteration
+    fn total(&self) -> i64 {
+        let span = (self.end - self.start).num_milliseconds();
+        let step = self.step.num_milliseconds();
+        match (span < 0, self.inclusive) {
+            (true, _) => 0,
+            (false, true) => span / step + 1,
+            (false, false) => (span + step - 1) / step,
+        }
+    }
+
+    fn remaining(&self) -> i64 {
+        (self.total() - self.front - self.back).max(0)
+    }
 
-impl Iterator for MinuteDateRange {
+    fn at(&self, steps: i64) -> DateTime<Utc> {
+        self.start + Duration::milliseconds(self.step.num_milliseconds() * steps)
+    }
+}
+
+impl Iterator for TimeRange {
     type Item = DateTime<Utc>;
+
     fn next(&mut self) -> Option<Self::Item> {
-        if self.0 <= self.1 {
-            let next = self.0 + Duration::minutes(1);
-            Some(std::mem::replace(&mut self.0, next))
-        } else {
-            None
+        if self.remaining() == 0 {
+            return None;
         }
+        self.front += 1;
+        Some(self.at(self.front - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.remaining() as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl DoubleEndedIterator for TimeRange {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining() == 0 {
+            return None;
+        }
+        self.back += 1;
+        Some(self.at(self.total() - self.back))
+    }
+}
+
+impl ExactSizeIterator for TimeRange {}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::TimeRange;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_time_range() {
+        let half_open: Vec<_> = TimeRange::minutes(at(18, 0), at(19, 0), 15).collect();
+        assert_eq!(half_open, [at(18, 0), at(18, 15), at(18, 30), at(18, 45)]);
+
+        let closed = TimeRange::minutes(at(18, 0), at(19, 0), 15).inclusive();
+        assert_eq!(closed.len(), 5);
+
+        let aligned: Vec<_> = TimeRange::minutes(at(18, 7), at(18, 45), 15)
+            .aligned()
+            .inclusive()
+            .rev()
+            .collect();
+        assert_eq!(aligned, [at(18, 45), at(18, 30), at(18, 15)]);
+
+        let mut range = TimeRange::new(at(18, 0), at(18, 50), Duration::minutes(20));
+        assert_eq!(range.next_back(), Some(at(18, 40)));
+        assert_eq!(range.next(), Some(at(18, 0)));
+        assert_eq!(range.next(), Some(at(18, 20)));
+        assert_eq!(range.next_back(), None);
+
+        assert_eq!(TimeRange::minutes(at(19, 0), at(18, 0), 15).len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least a millisecond")]
+    fn test_time_range_sub_millisecond_step() {
+        TimeRange::new(at(18, 0), at(19, 0), Duration::microseconds(500));
     }
 }
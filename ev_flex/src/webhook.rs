@@ -0,0 +1,457 @@
+use crate::aggregation::Aggregation;
+use crate::config::WebhookConfig;
+use crate::demand::{DemandEvent, Demands, EnergyDemand};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+pub const SIGNATURE_HEADER: &str = "X-EvFlex-Signature";
+pub const EVENT_HEADER: &str = "X-EvFlex-Event";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    DemandCreated,
+    DemandUpdated,
+    DemandDeleted,
+    DemandInfeasible,
+    DemandExpired,
+    AggregationChanged,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    DemandCreated(EnergyDemand),
+    DemandUpdated(EnergyDemand),
+    DemandDeleted { vehicle_id: String },
+    DemandInfeasible { vehicle_id: String },
+    DemandExpired { vehicle_id: String },
+    AggregationChanged(Aggregation),
+}
+
+impl Event {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            Event::DemandCreated(_) => EventType::DemandCreated,
+            Event::DemandUpdated(_) => EventType::DemandUpdated,
+            Event::DemandDeleted { .. } => EventType::DemandDeleted,
+            Event::DemandInfeasible { .. } => EventType::DemandInfeasible,
+            Event::DemandExpired { .. } => EventType::DemandExpired,
+            Event::AggregationChanged(_) => EventType::AggregationChanged,
+        }
+    }
+}
+
+impl From<DemandEvent> for Event {
+    fn from(event: DemandEvent) -> Self {
+        match event {
+            DemandEvent::Created(demand) => Event::DemandCreated(demand),
+            DemandEvent::Updated(demand) => Event::DemandUpdated(demand),
+            DemandEvent::Deleted(vehicle_id) => Event::DemandDeleted { vehicle_id },
+            DemandEvent::Infeasible(vehicle_id) => Event::DemandInfeasible { vehicle_id },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    id: u64,
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// A URL that is sent the events it asks for, signed with its secret.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Subscription {
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    #[serde(default)]
+    pub events: Vec<EventType>, // all events if empty
+}
+
+impl Subscription {
+    fn wants(&self, event_type: EventType) -> bool {
+        self.events.is_empty() || self.events.contains(&event_type)
+    }
+}
+
+/// Outcome of one attempt to deliver an event.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Delivery {
+    pub event_id: u64,
+    pub event_type: EventType,
+    pub attempt: usize,
+    pub time: DateTime<Utc>,
+    pub status: Option<u16>, // HTTP status of the response, if there was one
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+/// Hex encoded HMAC-SHA256 of the body, sent as `sha256=<signature>`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Largest change of the minimum or maximum state of charge at any time, in
+/// percent of the largest maximum state of charge of `previous`.
+pub fn envelope_change(previous: &Aggregation, current: &Aggregation) -> f64 {
+    let soe = |aggregation: &Aggregation| -> BTreeMap<DateTime<Utc>, (i64, i64)> {
+        aggregation
+            .series
+            .iter()
+            .map(|dt| (dt.time, (dt.min_soe, dt.max_soe)))
+            .collect()
+    };
+    let (previous, current) = (soe(previous), soe(current));
+    let times: HashSet<&DateTime<Utc>> = previous.keys().chain(current.keys()).collect();
+    let change = times
+        .into_iter()
+        .map(|time| {
+            let (min_a, max_a) = previous.get(time).copied().unwrap_or_default();
+            let (min_b, max_b) = current.get(time).copied().unwrap_or_default();
+            (min_a - min_b).abs().max((max_a - max_b).abs())
+        })
+        .max()
+        .unwrap_or(0);
+    let scale = previous.values().map(|(_, max)| *max).max().unwrap_or(0);
+    match scale {
+        0 if change == 0 => 0.0,
+        0 => 100.0,
+        scale => change as f64 * 100.0 / scale as f64,
+    }
+}
+
+struct Pending {
+    subscription: u64,
+    event_id: u64,
+    event_type: EventType,
+    body: Arc<Vec<u8>>,
+    attempt: usize,
+    due: Instant,
+}
+
+/// Webhook subscriptions and their delivery logs.
+///
+/// Events are delivered by a background thread, so that slow receivers never
+/// hold up requests. Failed deliveries are retried after the configured delays.
+pub struct Webhooks {
+    subscriptions: RwLock<BTreeMap<u64, Subscription>>,
+    deliveries: Mutex<BTreeMap<u64, VecDeque<Delivery>>>,
+    next_subscription: AtomicU64,
+    config: WebhookConfig,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Webhooks {
+    /// Listens to the changes of `db` and delivers events until `db` is dropped
+    /// or removes its listeners.
+    pub fn start(db: Arc<Demands>, config: WebhookConfig) -> Arc<Self> {
+        let webhooks = Arc::new(Webhooks {
+            subscriptions: RwLock::default(),
+            deliveries: Mutex::default(),
+            next_subscription: AtomicU64::new(1),
+            config,
+            worker: Mutex::default(),
+        });
+        let (sender, receiver) = channel();
+        db.add_listener(sender);
+        let worker = webhooks.clone();
+        let db = Arc::downgrade(&db);
+        let handle = thread::spawn(move || Worker::new(worker).run(receiver, db));
+        *webhooks.worker.lock().unwrap() = Some(handle);
+        webhooks
+    }
+
+    /// Waits for the worker to stop, which it does once the store has removed
+    /// its listeners. Deliveries still waiting for a retry are dropped.
+    pub fn join(&self) {
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            let _ = worker.join();
+        }
+    }
+
+    pub fn subscribe(&self, subscription: Subscription) -> u64 {
+        let id = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+        self.subscriptions.write().unwrap().insert(id, subscription);
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) -> Option<Subscription> {
+        self.deliveries.lock().unwrap().remove(&id);
+        self.subscriptions.write().unwrap().remove(&id)
+    }
+
+    pub fn subscriptions(&self) -> BTreeMap<u64, Subscription> {
+        self.subscriptions.read().unwrap().clone()
+    }
+
+    /// Latest delivery attempts of a subscription, oldest first.
+    pub fn deliveries(&self, id: u64) -> Option<Vec<Delivery>> {
+        if !self.subscriptions.read().unwrap().contains_key(&id) {
+            return None;
+        }
+        let deliveries = self.deliveries.lock().unwrap();
+        Some(deliveries.get(&id).into_iter().flatten().cloned().collect())
+    }
+
+    fn log(&self, subscription: u64, delivery: Delivery) {
+        if !self
+            .subscriptions
+            .read()
+            .unwrap()
+            .contains_key(&subscription)
+        {
+            return;
+        }
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let log = deliveries.entry(subscription).or_default();
+        log.push_back(delivery);
+        while log.len() > self.config.log_size {
+            log.pop_front();
+        }
+    }
+}
+
+struct Worker {
+    webhooks: Arc<Webhooks>,
+    pending: Vec<Pending>,
+    next_event: u64,
+    notified: Option<Aggregation>, // envelope of the last aggregation_changed event
+    expired: HashSet<(String, DateTime<Utc>)>,
+    next_expiry_check: Instant,
+}
+
+impl Worker {
+    fn new(webhooks: Arc<Webhooks>) -> Self {
+        Worker {
+            webhooks,
+            pending: vec![],
+            next_event: 1,
+            notified: None,
+            expired: HashSet::new(),
+            next_expiry_check: Instant::now(),
+        }
+    }
+
+    fn run(mut self, receiver: Receiver<DemandEvent>, db: Weak<Demands>) {
+        loop {
+            let next_due = self.pending.iter().map(|pending| pending.due).min();
+            let wake = next_due.map_or(self.next_expiry_check, |due| {
+                due.min(self.next_expiry_check)
+            });
+            let timeout = wake.saturating_duration_since(Instant::now());
+            let mut changed = false;
+            match receiver.recv_timeout(timeout) {
+                Ok(event) => {
+                    self.publish(&event.into());
+                    // handle everything that queued up before looking at the envelope
+                    while let Ok(event) = receiver.try_recv() {
+                        self.publish(&event.into());
+                    }
+                    changed = true;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let Some(db) = db.upgrade() else {
+                return;
+            };
+            if changed {
+                self.check_aggregation(&db);
+            }
+            if Instant::now() >= self.next_expiry_check {
+                self.check_expiry(&db);
+                self.next_expiry_check = Instant::now() + self.webhooks.config.expiry_interval;
+            }
+            drop(db);
+            self.deliver_due();
+        }
+    }
+
+    fn publish(&mut self, event: &Event) {
+        let event_type = event.event_type();
+        let subscriptions: Vec<u64> = self
+            .webhooks
+            .subscriptions
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, subscription)| subscription.wants(event_type))
+            .map(|(id, _)| *id)
+            .collect();
+        let event_id = self.next_event;
+        self.next_event += 1;
+        if subscriptions.is_empty() {
+            return;
+        }
+        let notification = Notification {
+            id: event_id,
+            time: Utc::now(),
+            event,
+        };
+        let body = Arc::new(serde_json::to_vec(&notification).unwrap());
+        for subscription in subscriptions {
+            self.pending.push(Pending {
+                subscription,
+                event_id,
+                event_type,
+                body: body.clone(),
+                attempt: 1,
+                due: Instant::now(),
+            });
+        }
+    }
+
+    fn check_aggregation(&mut self, db: &Demands) {
+        // the envelope is only worth computing for someone to send it to
+        let subscribed = self
+            .webhooks
+            .subscriptions
+            .read()
+            .unwrap()
+            .values()
+            .any(|subscription| subscription.wants(EventType::AggregationChanged));
+        if !subscribed {
+            return;
+        }
+        let aggregation = db.aggregation();
+        let change = match &self.notified {
+            Some(notified) => envelope_change(notified, &aggregation),
+            None => envelope_change(&Aggregation::default(), &aggregation),
+        };
+        if change > self.webhooks.config.aggregation_threshold {
+            let event = Event::AggregationChanged(aggregation);
+            self.publish(&event);
+            if let Event::AggregationChanged(aggregation) = event {
+                self.notified = Some(aggregation);
+            }
+        }
+    }
+
+    fn check_expiry(&mut self, db: &Demands) {
+        let now = Utc::now();
+        let demands = db.all();
+        // sessions that are no longer stored cannot expire again
+        let stored: HashSet<(String, DateTime<Utc>)> = demands
+            .iter()
+            .map(|demand| (demand.vehicle_id.clone(), demand.end))
+            .collect();
+        self.expired.retain(|session| stored.contains(session));
+        for demand in demands.into_iter().filter(|demand| demand.end <= now) {
+            if self.expired.insert((demand.vehicle_id.clone(), demand.end)) {
+                self.publish(&Event::DemandExpired {
+                    vehicle_id: demand.vehicle_id,
+                });
+            }
+        }
+    }
+
+    fn deliver_due(&mut self) {
+        let now = Instant::now();
+        let (due, waiting): (Vec<Pending>, Vec<Pending>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| pending.due <= now);
+        self.pending = waiting;
+        for pending in due {
+            if let Some(retry) = self.deliver(pending) {
+                self.pending.push(retry);
+            }
+        }
+    }
+
+    // attempts the delivery and returns the retry if it failed and retries are left
+    fn deliver(&self, mut pending: Pending) -> Option<Pending> {
+        let subscription = self
+            .webhooks
+            .subscriptions
+            .read()
+            .unwrap()
+            .get(&pending.subscription)
+            .cloned()?;
+        let event_type = serde_json::to_value(pending.event_type).unwrap();
+        let response = ureq::post(&subscription.url)
+            .timeout(self.webhooks.config.timeout)
+            .set("Content-Type", "application/json")
+            .set(EVENT_HEADER, event_type.as_str().unwrap())
+            .set(
+                SIGNATURE_HEADER,
+                &format!("sha256={}", sign(&subscription.secret, &pending.body)),
+            )
+            .send_bytes(&pending.body);
+        let (status, error) = match response {
+            Ok(response) => (Some(response.status()), None),
+            Err(ureq::Error::Status(status, _)) => (Some(status), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        let delivered = status.is_some_and(|status| (200..300).contains(&status));
+        self.webhooks.log(
+            pending.subscription,
+            Delivery {
+                event_id: pending.event_id,
+                event_type: pending.event_type,
+                attempt: pending.attempt,
+                time: Utc::now(),
+                status,
+                error,
+                delivered,
+            },
+        );
+        if delivered {
+            return None;
+        }
+        let delay = self.webhooks.config.retry_delays.get(pending.attempt - 1)?;
+        pending.attempt += 1;
+        pending.due = Instant::now() + *delay;
+        Some(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregation::{vehicle_aggregation, Aggregation};
+    use crate::demand::EnergyDemand;
+    use crate::webhook::{envelope_change, sign};
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        let signature = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            signature,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_envelope_change() {
+        let demand = EnergyDemand::example("a");
+        let aggregation = vehicle_aggregation(&demand);
+        assert_eq!(envelope_change(&aggregation, &aggregation), 0.0);
+        assert_eq!(
+            envelope_change(&Aggregation::default(), &aggregation),
+            100.0
+        );
+        let smaller = vehicle_aggregation(&EnergyDemand {
+            target_soc: 76,
+            ..demand
+        });
+        // 2.4 kWh less of the 48 kWh target
+        assert_eq!(envelope_change(&aggregation, &smaller), 5.0);
+    }
+}
@@ -0,0 +1,623 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, web::Data, App};
+use ev_flex::api::configure;
+use ev_flex::Schedules;
+use ev_flex::{Demands, EnergyDemand};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn demand(vehicle_id: &str, start: &str, end: &str) -> Value {
+    let time = |time: &str| time.parse().unwrap();
+    let demand = EnergyDemand::example_session(vehicle_id, time(start), time(end));
+    serde_json::to_value(demand).unwrap()
+}
+
+fn overnight(vehicle_id: &str) -> Value {
+    demand(vehicle_id, "2023-05-01T18:00:00Z", "2023-05-02T06:00:00Z")
+}
+
+macro_rules! init_app {
+    ($db:expr) => {
+        test::init_service(App::new().app_data($db.clone()).configure(configure)).await
+    };
+}
+
+macro_rules! post_demand {
+    ($app:expr, $demand:expr) => {{
+        let request = test::TestRequest::post()
+            .uri("/demand")
+            .set_json($demand)
+            .to_request();
+        String::from_utf8(test::call_and_read_body(&$app, request).await.to_vec()).unwrap()
+    }};
+}
+
+macro_rules! get_json {
+    ($app:expr, $uri:expr) => {{
+        let request = test::TestRequest::get().uri($uri).to_request();
+        let value: Value = test::call_and_read_body_json(&$app, request).await;
+        value
+    }};
+}
+
+#[actix_web::test]
+async fn test_submit_and_update_demand() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+
+    assert_eq!(post_demand!(app, overnight("a")), "Received demand for a!");
+    assert_eq!(post_demand!(app, overnight("a")), "Updated demand for a!");
+    assert_eq!(db.len(), 1);
+}
+
+#[actix_web::test]
+async fn test_invalid_demand_rejected() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+
+    let request = test::TestRequest::post()
+        .uri("/demand")
+        .set_json(json!({"vehicle_id": "a"}))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = test::TestRequest::post()
+        .uri("/demand")
+        .set_json(demand("a", "2023-05-02T06:00:00Z", "2023-05-01T18:00:00Z"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // ids with @ are kept for the sessions of recurring demands
+    let request = test::TestRequest::post()
+        .uri("/demand")
+        .set_json(overnight("bus@depot"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(db.is_empty());
+}
+
+#[actix_web::test]
+async fn test_bulk_import_export() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let backwards = demand("c", "2023-05-02T06:00:00Z", "2023-05-01T18:00:00Z");
+    let request = test::TestRequest::post()
+        .uri("/demands")
+        .set_json(json!([overnight("a"), overnight("b"), backwards, {"vehicle_id": "d"}]))
+        .to_request();
+    let results: Value = test::call_and_read_body_json(&app, request).await;
+    let statuses: Vec<&str> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["updated", "received", "rejected", "rejected"]);
+    assert_eq!(results[3]["vehicle_id"], "d");
+    assert!(results[3]["error"].as_str().unwrap().contains("min_soc"));
+
+    let request = test::TestRequest::get()
+        .uri("/demands")
+        .insert_header(("Accept", "application/x-ndjson"))
+        .to_request();
+    let export = test::call_and_read_body(&app, request).await;
+    assert_eq!(
+        String::from_utf8(export.to_vec()).unwrap().lines().count(),
+        2
+    );
+
+    // the export restores the store of another instance
+    let restored = Data::new(Demands::new());
+    let other = init_app!(restored);
+    let request = test::TestRequest::post()
+        .uri("/demands")
+        .set_payload(export)
+        .to_request();
+    let results: Value = test::call_and_read_body_json(&other, request).await;
+    assert_eq!(results.as_array().unwrap().len(), 2);
+    assert_eq!(get_json!(other, "/demands"), get_json!(app, "/demands"));
+    assert_eq!(
+        get_json!(other, "/aggregation"),
+        get_json!(app, "/aggregation")
+    );
+
+    let request = test::TestRequest::post()
+        .uri("/demands")
+        .set_payload("[{")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_bulk_import_beyond_default_payload_limit() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    let demands: Vec<Value> = (0..2000)
+        .map(|i| overnight(&format!("vehicle-{}", i)))
+        .collect();
+    let body = serde_json::to_vec(&demands).unwrap();
+    // the default limit of actix-web is 256 KiB
+    assert!(body.len() > 256 * 1024);
+    let request = test::TestRequest::post()
+        .uri("/demands")
+        .insert_header(("Content-Type", "application/json"))
+        .set_payload(body)
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(db.all().len(), 2000);
+}
+
+#[actix_web::test]
+async fn test_remove_demand() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let request = test::TestRequest::delete().uri("/demand/a").to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::OK
+    );
+    let request = test::TestRequest::delete().uri("/demand/a").to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let aggregation = get_json!(app, "/aggregation");
+    assert!(aggregation["series"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_aggregation_shape() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+    post_demand!(app, overnight("b"));
+
+    let aggregation = get_json!(app, "/aggregation");
+    assert_eq!(aggregation["start"], "2023-05-01T18:00:00Z");
+    assert_eq!(aggregation["end"], "2023-05-02T06:00:00Z");
+    let series = aggregation["series"].as_array().unwrap();
+    let flexibility = aggregation["flexibility"].as_array().unwrap();
+    assert_eq!(series.len(), 12 * 4 + 1);
+    assert_eq!(flexibility.len(), series.len() - 1);
+    for key in [
+        "min_soe",
+        "max_soe",
+        "max_charging_power",
+        "baseline_soe",
+        "baseline_power",
+        "time",
+    ] {
+        assert!(series[0].get(key).is_some(), "missing {}", key);
+    }
+    assert_eq!(series[0]["max_charging_power"], 2 * 11000);
+    assert_eq!(series[0]["baseline_power"], 2 * 11000);
+
+    let vehicle = get_json!(app, "/demand/a/series");
+    assert_eq!(vehicle["series"].as_array().unwrap().len(), series.len());
+    assert_eq!(
+        2 * vehicle["series"][10]["max_soe"].as_i64().unwrap(),
+        series[10]["max_soe"].as_i64().unwrap()
+    );
+}
+
+#[actix_web::test]
+async fn test_grouped_aggregation() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    let mut a = overnight("a");
+    a["grid_node"] = json!("node-1");
+    post_demand!(app, a);
+    post_demand!(app, overnight("b"));
+
+    let groups = get_json!(app, "/aggregation?group_by=grid_node");
+    let groups = groups.as_array().unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0]["group"], Value::Null);
+    assert_eq!(groups[1]["group"], "node-1");
+    assert!(groups[1]["series"].as_array().unwrap().len() > 1);
+
+    let request = test::TestRequest::get()
+        .uri("/aggregation?group_by=colour")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn test_degradation_policies() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    let mut a = overnight("a");
+    a["fleet"] = json!("depot");
+    post_demand!(app, a);
+    let mut b = overnight("b");
+    b["fleet"] = json!("depot");
+    b["degradation_policy"] = json!({"comfort_max_soc": 70});
+    post_demand!(app, b);
+
+    let last_max_soe = |aggregation: &Value| {
+        let series = aggregation["series"].as_array().unwrap();
+        series.last().unwrap()["max_soe"].as_i64().unwrap()
+    };
+    // 80% and 70% of 60 kWh
+    assert_eq!(last_max_soe(&get_json!(app, "/aggregation")), 48000 + 42000);
+
+    let request = test::TestRequest::put()
+        .uri("/fleet/depot/policy")
+        .set_json(json!({"max_cycle_depth": 40}))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::OK
+    );
+    // the fleet policy only applies to the vehicle without its own policy
+    assert_eq!(last_max_soe(&get_json!(app, "/aggregation")), 30000 + 42000);
+    assert_eq!(last_max_soe(&get_json!(app, "/demand/a/series")), 30000);
+
+    let request = test::TestRequest::put()
+        .uri("/fleet/depot/policy")
+        .set_json(json!({"max_cycle_depth": i32::MAX}))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(db.len(), 2);
+}
+
+#[actix_web::test]
+async fn test_confidence_aggregation() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    let mut a = overnight("a");
+    a["departure_distribution"] = json!([
+        {"time": "2023-05-02T02:00:00Z", "probability": 0.0},
+        {"time": "2023-05-02T04:00:00Z", "probability": 0.5},
+    ]);
+    post_demand!(app, a);
+    post_demand!(app, overnight("b"));
+
+    let expected = get_json!(app, "/aggregation");
+    let p50 = get_json!(app, "/aggregation?confidence=50");
+    let p90 = get_json!(app, "/aggregation?confidence=90");
+    let length = |aggregation: &Value| aggregation["series"].as_array().unwrap().len();
+    assert_eq!(length(&p50), length(&expected));
+    // with 90% confidence vehicle a stays until 02:24
+    assert_eq!(p90["series"][4 * 8 + 1]["time"], "2023-05-02T02:15:00Z");
+    assert_eq!(p90["series"][4 * 8 + 1]["max_charging_power"], 2 * 11000);
+    assert_eq!(p90["series"][4 * 8 + 2]["max_charging_power"], 11000);
+
+    let request = test::TestRequest::get()
+        .uri("/aggregation?confidence=75")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn test_infeasible_demand() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    // 42 kWh at 11 kW does not fit into one hour
+    let infeasible = demand("a", "2023-05-01T18:00:00Z", "2023-05-01T19:00:00Z");
+    assert_eq!(post_demand!(app, infeasible), "Received demand for a!");
+
+    let aggregation = get_json!(app, "/aggregation");
+    assert!(aggregation["series"].as_array().unwrap().is_empty());
+    let vehicle = get_json!(app, "/demand/a/series");
+    assert!(vehicle["series"].as_array().unwrap().is_empty());
+
+    post_demand!(app, overnight("b"));
+    let aggregation = get_json!(app, "/aggregation");
+    assert_eq!(aggregation["series"][0]["max_charging_power"], 11000);
+}
+
+#[actix_web::test]
+async fn test_vehicle_envelope() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let envelope = get_json!(app, "/demand/a/envelope");
+    // asap line and plateau, a few breakpoints instead of 720 rows
+    let max_soe = envelope["max_soe"].as_array().unwrap();
+    assert_eq!(max_soe.len(), 3);
+    assert_eq!(max_soe[2]["value"], 48000.0);
+    let series = get_json!(app, "/demand/a/series");
+    let last = &series["series"].as_array().unwrap()[48];
+    assert_eq!(last["max_soe"], 48000);
+    let min_soe = envelope["min_soe"].as_array().unwrap();
+    assert_eq!(
+        last["min_soe"].as_f64(),
+        min_soe.last().unwrap()["value"].as_f64()
+    );
+
+    let request = test::TestRequest::get()
+        .uri("/demand/b/envelope")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[actix_web::test]
+async fn test_market_timezone_alignment() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let aggregation = get_json!(
+        app,
+        "/aggregation?tz=Europe/Berlin&period=60&delivery_day=2023-05-02"
+    );
+    assert_eq!(aggregation["start"], "2023-05-01T22:00:00Z");
+    let series = aggregation["series"].as_array().unwrap();
+    assert_eq!(series[0]["time"], "2023-05-01T22:00:00Z");
+    assert_eq!(series[0]["local_time"], "2023-05-02T00:00:00+02:00");
+    assert_eq!(series.len(), 9);
+
+    let request = test::TestRequest::get()
+        .uri("/aggregation?tz=Nowhere/City")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn test_reserve_qualification() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let reserve = get_json!(app, "/reserve?product=afrr&block=60");
+    assert_eq!(reserve["product"], "afrr");
+    assert_eq!(reserve["duration_minutes"], 60);
+    let blocks = reserve["blocks"].as_array().unwrap();
+    assert_eq!(blocks.len(), 11);
+    // the vehicle has to reach its minimum SoC before charging can be paused
+    assert_eq!(blocks[0]["upward"], 0);
+    assert_eq!(blocks[1]["upward"], 11000);
+    assert!(blocks.iter().all(|block| block["downward"] == 0));
+
+    for uri in ["/reserve?product=mfrr", "/reserve?product=fcr&duration=0"] {
+        let request = test::TestRequest::get().uri(uri).to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_openapi_document() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let document = get_json!(app, "/openapi.json");
+    for path in ["/demand", "/demands", "/aggregation", "/reserve"] {
+        assert!(
+            document["paths"][path].is_object(),
+            "{} is not documented",
+            path
+        );
+    }
+    // the schema lists exactly the fields the server serializes
+    let demands = get_json!(app, "/demands");
+    let mut fields: Vec<&String> = demands[0].as_object().unwrap().keys().collect();
+    let schema = &document["components"]["schemas"]["EnergyDemand"]["properties"];
+    let mut properties: Vec<&String> = schema.as_object().unwrap().keys().collect();
+    fields.sort();
+    properties.sort();
+    assert_eq!(fields, properties);
+
+    let request = test::TestRequest::get().uri("/swagger-ui/").to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[actix_web::test]
+async fn test_export_formats() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+
+    let request = test::TestRequest::get()
+        .uri("/aggregation")
+        .insert_header(("Accept", "text/csv"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/csv");
+    let body = test::read_body(response).await;
+    assert!(body.starts_with(b"group,time,local_time,min_soe"));
+
+    let request = test::TestRequest::get()
+        .uri("/demand/a/series?format=parquet")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    if cfg!(feature = "parquet") {
+        assert!(test::read_body(response).await.starts_with(b"PAR1"));
+    } else {
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[actix_web::test]
+async fn test_concurrent_submissions() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    let requests = (0..50).map(|i| {
+        let request = test::TestRequest::post()
+            .uri("/demand")
+            .set_json(overnight(&format!("ev-{}", i % 25)))
+            .to_request();
+        test::call_service(&app, request)
+    });
+    for response in futures::future::join_all(requests).await {
+        assert!(response.status().is_success());
+    }
+
+    let aggregation = get_json!(app, "/aggregation");
+    let expected = Demands::new();
+    for i in 0..25 {
+        let demand: EnergyDemand = serde_json::from_value(overnight(&format!("ev-{}", i))).unwrap();
+        expected.insert(demand);
+    }
+    assert_eq!(
+        aggregation,
+        serde_json::to_value(expected.aggregation()).unwrap()
+    );
+}
+
+#[actix_web::test]
+async fn test_concurrent_store_updates() {
+    let db = Arc::new(Demands::new());
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..20 {
+                    let vehicle_id = format!("ev-{}-{}", thread, i);
+                    let demand: EnergyDemand =
+                        serde_json::from_value(overnight(&vehicle_id)).unwrap();
+                    db.insert(demand.clone());
+                    db.insert(demand);
+                    if i % 2 == 0 {
+                        db.remove(&vehicle_id);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(db.len(), 8 * 10);
+    let aggregation = db.aggregation();
+    let single: EnergyDemand = serde_json::from_value(overnight("single")).unwrap();
+    let expected = Demands::new();
+    expected.insert(single);
+    for (dt, single) in aggregation.series.iter().zip(expected.aggregation().series) {
+        assert_eq!(dt.max_soe, 80 * single.max_soe);
+        assert_eq!(dt.min_soe, 80 * single.min_soe);
+    }
+}
+
+#[actix_web::test]
+async fn test_recurring_templates() {
+    let db = Data::new(Demands::new());
+    let schedules = Data::new(Schedules::new(chrono::Duration::hours(48)));
+    let app = test::init_service(
+        App::new()
+            .app_data(db.clone())
+            .app_data(schedules.clone())
+            .configure(configure),
+    )
+    .await;
+    let mut template = json!({
+        "vehicle_id": "bus",
+        "weekdays": [],
+        "arrival": "18:00:00",
+        "departure": "06:00:00",
+        "expected_soc": 30,
+        "min_soc": 20,
+        "max_soc": 100,
+        "target_soc": 80,
+        "capacity": 300000,
+        "max_charging_power": 150000,
+    });
+    let request = test::TestRequest::post()
+        .uri("/templates")
+        .set_json(&template)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    template["weekdays"] = json!(["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]);
+    let request = test::TestRequest::post()
+        .uri("/templates")
+        .set_json(&template)
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    assert_eq!(body, "Received template for bus!");
+
+    // a daily overnight session over the two days from Friday noon
+    let friday = "2023-05-05T12:00:00Z".parse().unwrap();
+    schedules.refresh(&db, friday);
+    let demands = get_json!(app, "/demands");
+    let sessions = demands.as_array().unwrap();
+    let ids: Vec<_> = sessions.iter().map(|s| &s["vehicle_id"]).collect();
+    assert_eq!(ids, ["bus@2023-05-05", "bus@2023-05-06"]);
+    assert!(sessions.iter().all(|s| s["current_soc"] == 30));
+    let aggregation = get_json!(app, "/aggregation");
+    assert!(!aggregation["series"].as_array().unwrap().is_empty());
+
+    let request = test::TestRequest::delete()
+        .uri("/templates/bus")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::OK
+    );
+    assert!(db.is_empty());
+}
+
+#[actix_web::test]
+async fn test_settlement_report() {
+    let db = Data::new(Demands::new());
+    let app = init_app!(db);
+    post_demand!(app, overnight("a"));
+    post_demand!(app, overnight("b"));
+
+    for (vehicle_id, soc, status) in [
+        ("a", 85, StatusCode::OK),
+        ("a", 101, StatusCode::BAD_REQUEST),
+        ("c", 85, StatusCode::NOT_FOUND),
+    ] {
+        let request = test::TestRequest::post()
+            .uri(&format!("/demand/{}/soc", vehicle_id))
+            .set_json(json!({"time": "2023-05-02T05:00:00Z", "soc": soc}))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), status);
+    }
+
+    let sessions = get_json!(app, "/settlement?from=2023-05-01");
+    assert_eq!(sessions[0]["planned_energy"], 42000);
+    assert_eq!(sessions[0]["measured_energy"], 45000);
+    assert_eq!(sessions[0]["target_reached"], true);
+    assert_eq!(sessions[1]["target_reached"], Value::Null);
+
+    let days = get_json!(app, "/settlement?from=2023-04-30&to=2023-05-02&level=day");
+    assert_eq!(days.as_array().unwrap().len(), 1);
+    assert_eq!(days[0]["sessions"], 2);
+    assert_eq!(days[0]["targets_reached"], 1);
+
+    let request = test::TestRequest::get()
+        .uri("/settlement?from=2023-05-01&format=csv")
+        .to_request();
+    let csv = String::from_utf8(test::call_and_read_body(&app, request).await.to_vec()).unwrap();
+    assert!(csv.starts_with("vehicle_id,day,start,end,"));
+    assert_eq!(csv.lines().count(), 3);
+}
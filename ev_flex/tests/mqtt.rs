@@ -0,0 +1,169 @@
+use bytes::BytesMut;
+use ev_flex::mqtt;
+use ev_flex::MqttConfig;
+use ev_flex::{Demands, EnergyDemand};
+use rumqttc::{
+    matches, read, AsyncClient, ConnAck, ConnectReturnCode, Event, MqttOptions, Packet, PubAck,
+    Publish, QoS, SubAck, SubscribeReasonCode,
+};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+type Subscribers = Arc<Mutex<Vec<(String, UnboundedSender<Publish>)>>>;
+
+// Just enough of an MQTT 3.1.1 broker to route QoS 0 and 1 publishes between
+// the clients of a test, so that no external broker is needed.
+async fn spawn_broker() -> (u16, Subscribers) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let subscribers: Subscribers = Arc::default();
+    let shared = subscribers.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, shared.clone()));
+        }
+    });
+    (port, subscribers)
+}
+
+async fn serve(stream: TcpStream, subscribers: Subscribers) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = unbounded_channel::<Publish>();
+    let (replies, mut outgoing) = unbounded_channel::<BytesMut>();
+    tokio::spawn(async move {
+        loop {
+            let mut buffer = tokio::select! {
+                Some(buffer) = outgoing.recv() => buffer,
+                Some(publish) = receiver.recv() => {
+                    let mut buffer = BytesMut::new();
+                    Publish::new(publish.topic, QoS::AtMostOnce, publish.payload.to_vec())
+                        .write(&mut buffer)
+                        .unwrap();
+                    buffer
+                }
+                else => return,
+            };
+            if writer.write_all_buf(&mut buffer).await.is_err() {
+                return;
+            }
+        }
+    });
+    let mut buffer = BytesMut::new();
+    loop {
+        let packet = match read(&mut buffer, 1 << 20) {
+            Ok(packet) => packet,
+            Err(_) => match reader.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+        };
+        let mut reply = BytesMut::new();
+        match packet {
+            Packet::Connect(_) => {
+                ConnAck::new(ConnectReturnCode::Success, false)
+                    .write(&mut reply)
+                    .unwrap();
+            }
+            Packet::Subscribe(subscribe) => {
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                    .collect();
+                SubAck::new(subscribe.pkid, codes)
+                    .write(&mut reply)
+                    .unwrap();
+                let mut subscribers = subscribers.lock().unwrap();
+                for filter in subscribe.filters {
+                    subscribers.push((filter.path, sender.clone()));
+                }
+            }
+            Packet::Publish(publish) => {
+                if publish.qos != QoS::AtMostOnce {
+                    PubAck::new(publish.pkid).write(&mut reply).unwrap();
+                }
+                for (filter, subscriber) in subscribers.lock().unwrap().iter() {
+                    if matches(&publish.topic, filter) {
+                        let _ = subscriber.send(publish.clone());
+                    }
+                }
+            }
+            Packet::PingReq => reply.extend_from_slice(&[0xd0, 0x00]),
+            Packet::Disconnect => return,
+            _ => {}
+        }
+        if !reply.is_empty() && replies.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_mqtt_ingestion() {
+    let (port, subscribers) = spawn_broker().await;
+    let db = Arc::new(Demands::new());
+    let mut config = MqttConfig::new("127.0.0.1", port);
+    config.demand_topics = vec!["depot/+/demand".to_string()];
+    let ingestion = tokio::spawn(mqtt::run(db.clone(), config));
+
+    let mut options = MqttOptions::new("telematics", "127.0.0.1", port);
+    options.set_max_packet_size(1 << 20, 1 << 20);
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    client
+        .subscribe("ev_flex/aggregation", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    let (published, mut aggregations) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(event) = eventloop.poll().await {
+            if let Event::Incoming(Packet::Publish(publish)) = event {
+                let _ = published.send(publish);
+            }
+        }
+    });
+    // wait for both clients to subscribe
+    while subscribers.lock().unwrap().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let valid = EnergyDemand::example("b");
+    let invalid = EnergyDemand::example_session("a", valid.end, valid.start);
+    for payload in [
+        b"{".to_vec(),
+        serde_json::to_vec(&invalid).unwrap(),
+        serde_json::to_vec(&valid).unwrap(),
+    ] {
+        client
+            .publish("depot/1/demand", QoS::AtLeastOnce, false, payload)
+            .await
+            .unwrap();
+    }
+
+    let publish = tokio::time::timeout(Duration::from_secs(5), aggregations.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let aggregation: Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(aggregation["series"][0]["max_charging_power"], 11000);
+    assert_eq!(db.len(), 1);
+    assert!(db.contains("b"));
+
+    // changes made elsewhere are published as well
+    db.insert(EnergyDemand::example("c"));
+    let publish = tokio::time::timeout(Duration::from_secs(5), aggregations.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let aggregation: Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(aggregation["series"][0]["max_charging_power"], 22000);
+
+    db.remove_listeners();
+    tokio::time::timeout(Duration::from_secs(5), ingestion)
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,81 @@
+use chrono::{Duration, TimeZone, Utc};
+use ev_flex::EnergyDemand;
+use ev_flex::{create_flex_series, vehicle_series, AggregationDT};
+use proptest::prelude::*;
+
+prop_compose! {
+    fn energy_demand()(
+        capacity in 10_000..120_000i32,
+        max_charging_power in 1_000..50_000i32,
+        current_soc in 0..=100i32,
+        start_minute in 0..(24 * 60i64),
+        duration_minutes in 1..(24 * 60i64),
+    )(
+        target_soc in current_soc..=100,
+        min_soc in 0..=100i32,
+        capacity in Just(capacity),
+        max_charging_power in Just(max_charging_power),
+        current_soc in Just(current_soc),
+        start_minute in Just(start_minute),
+        duration_minutes in Just(duration_minutes),
+    ) -> EnergyDemand {
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap()
+            + Duration::minutes(start_minute);
+        EnergyDemand {
+            vehicle_id: "ev".to_string(),
+            min_soc: min_soc.min(target_soc),
+            max_soc: 100,
+            target_soc,
+            current_soc,
+            capacity,
+            max_charging_power,
+            start,
+            end: start + Duration::minutes(duration_minutes),
+            fleet: None,
+            charger_type: None,
+            grid_node: None,
+            customer: None,
+            degradation_policy: None,
+            departure_distribution: None,
+        }
+    }
+}
+
+fn assert_envelope(series: &[AggregationDT]) -> Result<(), TestCaseError> {
+    for dt in series {
+        prop_assert!(
+            dt.min_soe <= dt.max_soe,
+            "min_soe above max_soe at {}",
+            dt.time
+        );
+    }
+    for window in series.windows(2) {
+        prop_assert!(window[0].time <= window[1].time);
+        prop_assert!(window[0].min_soe <= window[1].min_soe, "min_soe decreasing");
+        prop_assert!(window[0].max_soe <= window[1].max_soe, "max_soe decreasing");
+        prop_assert!(
+            window[0].baseline_soe <= window[1].baseline_soe,
+            "baseline decreasing"
+        );
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn test_flex_series_envelope(demand in energy_demand()) {
+        if let Some(series) = create_flex_series(&demand) {
+            assert_envelope(&series)?;
+        }
+    }
+
+    #[test]
+    fn test_vehicle_series_envelope(demand in energy_demand()) {
+        if let Some(series) = vehicle_series(&demand) {
+            assert_envelope(&series)?;
+            for dt in &series {
+                prop_assert!(dt.time >= demand.start && dt.time <= demand.end);
+            }
+        }
+    }
+}
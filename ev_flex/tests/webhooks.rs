@@ -0,0 +1,147 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, web::Data, App};
+use ev_flex::api::configure;
+use ev_flex::webhook::{sign, Webhooks, EVENT_HEADER, SIGNATURE_HEADER};
+use ev_flex::Demands;
+use ev_flex::WebhookConfig;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+struct Received {
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+// Receives webhook requests, answering the first with a server error.
+fn spawn_receiver() -> (String, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for (count, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = HashMap::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => headers.insert(name.to_lowercase(), value.to_string()),
+                    None => break,
+                };
+            }
+            let length = headers["content-length"].parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let status = match count {
+                0 => "500 Internal Server Error",
+                _ => "200 OK",
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            let _ = sender.send(Received { headers, body });
+        }
+    });
+    (url, receiver)
+}
+
+#[actix_web::test]
+async fn test_webhook_delivery() {
+    let db = Arc::new(Demands::new());
+    let config = WebhookConfig {
+        retry_delays: vec![Duration::from_millis(10)],
+        ..WebhookConfig::default()
+    };
+    let webhooks = Data::from(Webhooks::start(db.clone(), config));
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::from(db))
+            .app_data(webhooks.clone())
+            .configure(configure),
+    )
+    .await;
+    let (url, received) = spawn_receiver();
+
+    let request = test::TestRequest::post()
+        .uri("/webhooks")
+        .set_json(json!({"url": "ftp://example.com", "secret": "s"}))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let request = test::TestRequest::post()
+        .uri("/webhooks")
+        .set_json(json!({"url": url, "secret": "secret", "events": ["demand_created"]}))
+        .to_request();
+    let subscription: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(subscription["id"], 1);
+    assert!(subscription.get("secret").is_none());
+
+    let request = test::TestRequest::post()
+        .uri("/demand")
+        .set_json(json!({
+            "vehicle_id": "a",
+            "min_soc": 20,
+            "max_soc": 100,
+            "target_soc": 80,
+            "current_soc": 10,
+            "capacity": 60000,
+            "max_charging_power": 11000,
+            "start": "2023-05-01T18:00:00Z",
+            "end": "2023-05-02T06:00:00Z",
+        }))
+        .to_request();
+    test::call_service(&app, request).await;
+
+    // the failed first attempt is retried with the same event
+    let timeout = Duration::from_secs(5);
+    let first = received.recv_timeout(timeout).unwrap();
+    let retry = received.recv_timeout(timeout).unwrap();
+    assert_eq!(first.body, retry.body);
+    assert_eq!(
+        retry.headers[&EVENT_HEADER.to_lowercase()],
+        "demand_created"
+    );
+    assert_eq!(
+        retry.headers[&SIGNATURE_HEADER.to_lowercase()],
+        format!("sha256={}", sign("secret", &retry.body))
+    );
+    let event: Value = serde_json::from_slice(&retry.body).unwrap();
+    assert_eq!(event["type"], "demand_created");
+    assert_eq!(event["data"]["vehicle_id"], "a");
+
+    // the log is written right after each attempt
+    let mut deliveries: Vec<Value> = vec![];
+    for _ in 0..100 {
+        let request = test::TestRequest::get()
+            .uri("/webhooks/1/deliveries")
+            .to_request();
+        deliveries = test::call_and_read_body_json(&app, request).await;
+        if deliveries.len() == 2 {
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    let statuses: Vec<(Value, Value)> = deliveries
+        .iter()
+        .map(|delivery| (delivery["status"].clone(), delivery["delivered"].clone()))
+        .collect();
+    assert_eq!(
+        statuses,
+        [(json!(500), json!(false)), (json!(200), json!(true))]
+    );
+    assert_eq!(deliveries[1]["attempt"], 2);
+}